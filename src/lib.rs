@@ -0,0 +1,646 @@
+extern crate cargo_lock;
+#[macro_use]
+extern crate clap;
+extern crate iron;
+#[macro_use]
+extern crate log;
+extern crate logger;
+#[macro_use]
+extern crate router;
+extern crate rusqlite;
+extern crate scoped_threadpool;
+extern crate semver;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate simple_logger;
+extern crate walkdir;
+extern crate humantime;
+extern crate tokio;
+extern crate sha2;
+extern crate hmac;
+
+use std::cmp;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+mod crates;
+mod git;
+mod index_sync;
+pub mod stats;
+
+use clap::{App, Arg};
+
+// Iron Stuff
+use iron::prelude::*;
+use iron::status;
+use iron::AfterMiddleware;
+use iron::headers::{ByteRangeSpec, CacheControl, CacheDirective, ContentRange, ContentRangeSpec,
+                    Range};
+use logger::Logger;
+use router::Router;
+
+use iron::mime::{Mime, SubLevel, TopLevel};
+
+use crates::{fetch, pre_fetch, size};
+use stats::{Database, StatsSender};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    all: bool,
+    prefetch_path: Option<String>,
+    index_path: String,
+    crate_path: String,
+    git_index_path: String,
+    upstream: String,
+    index: String,
+    extern_url: String,
+    port: u16,
+    refresh_interval: Duration,
+    threads: u32,
+    log_level: log::Level,
+    hit_ratio_alert: Option<f64>,
+    stats_sample_rate: u32,
+    stats_flush_interval: Duration,
+    pushgateway_url: Option<String>,
+    pushgateway_job: String,
+    stats_queue_path: Option<String>,
+}
+
+impl Config {
+    pub fn init() -> Config {
+        let matches = App::new("cargo-cacher")
+            .version(crate_version!())
+            .about(
+                r#"Cargo-cacher is a caching proxy for Cargo, Rust's package manager.
+
+    The cacher can be used easily by setting your $HOME/.cargo/config to:
+
+    `
+    [source]
+
+    [source.crates-io]
+    replace-with = "mirror"
+
+    [source.mirror]
+    registry = "http://localhost:8080/index"
+    `"#,
+            )
+            .arg(
+                Arg::with_name("debug")
+                    .short("d")
+                    .multiple(true)
+                    .help("Sets the level of debugging information"),
+            )
+            .arg(
+                Arg::with_name("git")
+                    .short("g")
+                    .required(false)
+                    .takes_value(true)
+                    .help(
+                        "Upstream git index (Default: \
+                         https://github.com/rust-lang/crates.io-index.git)",
+                    ),
+            )
+            .arg(
+                Arg::with_name("index")
+                    .long("index")
+                    .short("i")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Path to store the indexes (git and crates) at (Default: $HOME/.crates)"),
+            )
+            .arg(
+                Arg::with_name("upstream")
+                    .long("upstream")
+                    .short("u")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Upstream Crate source (Default: https://static.crates.io/crates/)"),
+            )
+            .arg(
+                Arg::with_name("port")
+                    .long("port")
+                    .short("p")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Port to listen on (Default: 8080)"),
+            )
+            .arg(
+                Arg::with_name("extern-url")
+                    .long("eurl")
+                    .short("e")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Externally reachable URL (Default: http://localhost:8080)")
+            )
+            .arg(
+                Arg::with_name("refresh")
+                    .short("r")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Refresh interval for the git index (Default: 10 minutes)"),
+            )
+            .arg(
+                Arg::with_name("prefetch")
+                    .short("f")
+                    .takes_value(true)
+                    .required(false)
+                    .help("Path with a list of crate_name=version OR lock file to pre-fetch"),
+            )
+            .arg(
+                Arg::with_name("threads")
+                    .short("t")
+                    .help("How many threads to use to fetch crates in the background (Default: 16)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("all")
+                    .long("all")
+                    .short("a")
+                    .help("Prefetch entire Cargo index"),
+            )
+            .arg(
+                Arg::with_name("hit-ratio-alert")
+                    .long("hit-ratio-alert")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Warn when the hourly cache hit ratio drops below this value, ex: 0.5"),
+            )
+            .arg(
+                Arg::with_name("sample-rate")
+                    .long("sample-rate")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Only record 1 in N requests in the statistics (Default: 1)"),
+            )
+            .arg(
+                Arg::with_name("flush-interval")
+                    .long("flush-interval")
+                    .required(false)
+                    .takes_value(true)
+                    .help("How often buffered statistics are written out (Default: 5 seconds)"),
+            )
+            .arg(
+                Arg::with_name("pushgateway")
+                    .long("pushgateway")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Push statistics to this Prometheus Pushgateway URL"),
+            )
+            .arg(
+                Arg::with_name("pushgateway-job")
+                    .long("pushgateway-job")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Job label for pushed statistics (Default: cargo-cacher)"),
+            )
+            .arg(
+                Arg::with_name("stats-queue")
+                    .long("stats-queue")
+                    .required(false)
+                    .takes_value(true)
+                    .help("File keeping not yet written statistics across crashes"),
+            )
+            .get_matches();
+
+        let log_level = match matches.occurrences_of("debug") {
+            0 => log::Level::Warn,
+            1 => log::Level::Info,
+            2 => log::Level::Debug,
+            3 | _ => log::Level::Trace,
+        };
+        let default_crate_path = format!("{}/.crates", dirs::home_dir().unwrap().to_str().unwrap());
+        let index_path: String = matches
+            .value_of("index")
+            .unwrap_or(&default_crate_path)
+            .into();
+
+        let mut crate_path = index_path.clone();
+        crate_path.push_str("/crates");
+        let mut git_index: String = index_path.clone();
+        git_index.push_str("/index");
+        let port = u16::from_str(matches.value_of("port")
+                    .unwrap_or("8080"))
+                .unwrap_or(8080);
+        let refresh_interval_human = matches.value_of("refresh")
+            .unwrap_or("10 minutes")
+            .parse::<humantime::Duration>();
+        let refresh_interval_seconds = u64::from_str(matches.value_of("refresh").unwrap_or("600"));
+        let refresh_interval = match (refresh_interval_human, refresh_interval_seconds) {
+            (Ok(d), _) => d.into(),
+            (_, Ok(s)) => Duration::new(s, 0),
+            (Err(e), _) => {
+                eprintln!("Error while parsing refresh interval: {}.", e);
+                eprintln!("Try values like \"600s\" or \"2 hours\".");
+                exit(-1);
+            }
+        };
+        let stats_flush_interval = match matches.value_of("flush-interval")
+            .unwrap_or("5 seconds")
+            .parse::<humantime::Duration>() {
+            Ok(d) => d.into(),
+            Err(e) => {
+                eprintln!("Error while parsing flush interval: {}.", e);
+                eprintln!("Try values like \"5s\" or \"1 minute\".");
+                exit(-1);
+            }
+        };
+        Config {
+            all: matches.is_present("all"),
+            prefetch_path: matches.value_of("prefetch").map(|r| r.to_string()),
+            index_path: index_path,
+            crate_path: crate_path,
+            git_index_path: git_index,
+            upstream: matches
+                .value_of("upstream")
+                .unwrap_or("https://static.crates.io/crates/")
+                .into(),
+            index: matches
+                .value_of("git")
+                .unwrap_or("https://github.com/rust-lang/crates.io-index.git")
+                .into(),
+            port: u16::from_str(matches.value_of("port").unwrap_or("8080")).unwrap_or(8080),
+            extern_url: matches.value_of("extern-url")
+                .map(Into::into)
+                .unwrap_or(format!("http://localhost:{}", port)),
+            refresh_interval: refresh_interval,
+            threads: u32::from_str(matches.value_of("threads").unwrap_or("16")).unwrap_or(16),
+            log_level: log_level,
+            hit_ratio_alert: matches
+                .value_of("hit-ratio-alert")
+                .and_then(|r| f64::from_str(r).ok()),
+            stats_sample_rate: u32::from_str(matches.value_of("sample-rate").unwrap_or("1"))
+                .unwrap_or(1)
+                .max(1),
+            stats_flush_interval,
+            pushgateway_url: matches.value_of("pushgateway").map(Into::into),
+            pushgateway_job: matches.value_of("pushgateway-job")
+                .unwrap_or("cargo-cacher")
+                .into(),
+            stats_queue_path: matches.value_of("stats-queue").map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CargoRequest {
+    /// crate name, ex: cargo-cacher
+    name: String,
+    /// major.minor.patch
+    version: String,
+    /// Cache hit?
+    hit: bool,
+    /// Filesize in bytes
+    size: i64,
+    /// Requesting client, ex: its IP address
+    client: Option<String>,
+    /// Range (partial) download? `size` then only covers the transferred bytes
+    is_range: bool,
+    /// Crate whose build pulled this one in, when known
+    requested_by: Option<String>,
+    /// Client asked to bypass the cache, ex: `Cache-Control: no-store`
+    bypass: bool,
+    /// Milliseconds spent fetching from upstream, misses only
+    fetch_ms: Option<i64>,
+    /// Miss that failed because upstream was unreachable
+    upstream_down: bool,
+    /// The crate couldn't be served at all
+    failed: bool,
+    /// Build target the client is compiling for, ex: `aarch64-unknown-linux-gnu`
+    target: Option<String>,
+    /// When the stats collector was handed the request
+    received_at: Option<SystemTime>,
+}
+
+/// Parses the command line and serves until the process is stopped.
+pub fn run() {
+    let config = Config::init();
+
+    simple_logger::init_with_level(config.log_level).unwrap();
+    info!("Configuration: {:?}", config);
+
+    setup_filesystem(&config);
+
+    index_sync::init_sync(PathBuf::from(&config.git_index_path), &config);
+
+    pre_fetch(&config);
+    let collector = stats::stat_collector(&config);
+    server(&config, collector)
+}
+
+fn setup_filesystem(config: &Config) {
+    let _ = std::fs::create_dir_all(&config.crate_path);
+    let _ = std::fs::create_dir_all(&config.git_index_path);
+}
+
+struct CorsMiddleware;
+
+impl AfterMiddleware for CorsMiddleware {
+    fn after(&self, _req: &mut Request, mut res: Response) -> IronResult<Response> {
+        res.headers
+            .set(iron::headers::AccessControlAllowOrigin::Any);
+        Ok(res)
+    }
+}
+
+fn server(config: &Config, stats: StatsSender) {
+    // web server to handle DL requests
+    let host = format!(":::{}", config.port);
+    let router = router!(
+        stats_json: get "/stats.json" => {
+            let stats = stats.clone();
+            move |_request: &mut Request|
+                stats_json(&stats)
+        },
+        stats: get "/stats" => {
+            let stats = stats.clone();
+            move |_request: &mut Request|
+                stats_view(&stats)
+        },
+        download: get "api/v1/crates/:crate_name/:crate_version/download" => {
+            let config = config.clone();
+            let stats = Mutex::new(stats.clone());
+            move |request: &mut Request|
+                fetch_download(request, &config, &stats)
+        },
+        head: get "index/*" => {
+            let config = config.clone();
+            move |request: &mut Request|
+                git::git(request, &config)
+        },
+        index: get "index/**/*" => {
+            let config = config.clone();
+            move |request: &mut Request|
+                git::git(request, &config)
+        },
+        head: post "index/*" => {
+            let config = config.clone();
+            move |request: &mut Request|
+                git::git(request, &config)
+        },
+        index: post "index/**/*" => {
+            let config = config.clone();
+            move |request: &mut Request|
+                git::git(request, &config)
+        },
+        root: any "/" => log,
+        query: any "/*" => log,
+    );
+    let mut chain = Chain::new(router);
+    let (logger_before, logger_after) = Logger::new(None);
+    chain.link_before(logger_before);
+    chain.link_after(logger_after);
+
+    chain.link_after(CorsMiddleware);
+    println!("Listening on {}", host);
+    // Iron::new(chain).http(host).unwrap();
+    Iron::new(chain).http(&host[..]).unwrap();
+}
+
+pub fn log(req: &mut Request) -> IronResult<Response> {
+    info!("Whoops! {:?}", req);
+    Ok(Response::with((status::Ok, "Ok")))
+}
+
+fn fetch_download(
+    req: &mut Request,
+    config: &Config,
+    stats: &Mutex<StatsSender>,
+) -> IronResult<Response> {
+    let stats = stats.lock().unwrap();
+    let ref crate_name = req
+        .extensions
+        .get::<Router>()
+        .unwrap()
+        .find("crate_name")
+        .unwrap();
+    let ref crate_version = req
+        .extensions
+        .get::<Router>()
+        .unwrap()
+        .find("crate_version")
+        .unwrap();
+    let client = req.remote_addr.ip().to_string();
+    let bypass = match req.headers.get::<CacheControl>() {
+        Some(directives) => directives.iter()
+            .any(|d| *d == CacheDirective::NoStore || *d == CacheDirective::NoCache),
+        None => false,
+    };
+    let target = req.headers
+        .get_raw("X-Cargo-Target")
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok());
+    let range = req.headers.get::<Range>().cloned();
+    debug!("Downloading: {}:{}", crate_name, crate_version);
+    trace!("Raw request: {:?}", req);
+    let path = PathBuf::from(format!(
+        "{}/crates/{}/{}",
+        config.index_path, crate_name, crate_version
+    ));
+    if path.exists() {
+        debug!("path {:?} exists!", path);
+        let len = size(&path);
+        let bytes = requested_bytes(range.as_ref(), len);
+        stats.send(CargoRequest {
+            name: crate_name.to_string(),
+            version: crate_version.to_string(),
+            hit: true,
+            size: served_size(bytes, len),
+            client: Some(client),
+            is_range: bytes.is_some(),
+            bypass,
+            target,
+            ..Default::default()
+        });
+        serve_crate(path, bytes)
+    } else {
+        debug!("path {:?} doesn't exist!", path);
+
+        let started = Instant::now();
+        match fetch(
+            &path,
+            &config.upstream,
+            &config.index_path,
+            &crate_name,
+            &crate_version,
+        ) {
+            Ok(ref exit) if exit.success() => {
+                let len = size(&path);
+                let bytes = requested_bytes(range.as_ref(), len);
+                stats.send(CargoRequest {
+                    name: crate_name.to_string(),
+                    version: crate_version.to_string(),
+                    hit: false,
+                    size: served_size(bytes, len),
+                    client: Some(client),
+                    is_range: bytes.is_some(),
+                    bypass,
+                    target,
+                    fetch_ms: Some(started.elapsed().as_millis() as i64),
+                    ..Default::default()
+                });
+                serve_crate(path, bytes)
+            }
+            result => {
+                // curl itself failing means upstream couldn't be reached.
+                let upstream_down = match result {
+                    Ok(exit) => {
+                        error!("Fetching {}:{} failed with {}", crate_name, crate_version, exit);
+                        true
+                    }
+                    Err(e) => {
+                        error!("{:?}", e);
+                        false
+                    }
+                };
+                stats.send(CargoRequest {
+                    name: crate_name.to_string(),
+                    version: crate_version.to_string(),
+                    hit: false,
+                    client: Some(client),
+                    bypass,
+                    target,
+                    upstream_down,
+                    failed: true,
+                    ..Default::default()
+                });
+                return Ok(Response::with((
+                    status::ServiceUnavailable,
+                    "Couldn't fetch from Crates.io",
+                )));
+            }
+        }
+    }
+
+    // Ok(Response::with((status::Ok, "Ok")))
+}
+
+/// First and last byte a `Range` header asks for out of a `len` bytes crate.
+/// Only a single satisfiable byte range is served partially, anything else
+/// gets the whole crate.
+fn requested_bytes(range: Option<&Range>, len: u64) -> Option<(u64, u64)> {
+    let spec = match range {
+        Some(Range::Bytes(specs)) if specs.len() == 1 => &specs[0],
+        _ => return None,
+    };
+    match *spec {
+        ByteRangeSpec::FromTo(from, to) if from <= to && from < len => {
+            Some((from, cmp::min(to, len - 1)))
+        }
+        ByteRangeSpec::AllFrom(from) if from < len => Some((from, len - 1)),
+        ByteRangeSpec::Last(count) if count > 0 && len > 0 => {
+            Some((len.saturating_sub(count), len - 1))
+        }
+        _ => None,
+    }
+}
+
+/// Bytes sent by `serve_crate`, only the range for a partial download.
+fn served_size(bytes: Option<(u64, u64)>, len: u64) -> i64 {
+    match bytes {
+        Some((first, last)) => (last - first + 1) as i64,
+        None => len as i64,
+    }
+}
+
+fn serve_crate(path: PathBuf, bytes: Option<(u64, u64)>) -> IronResult<Response> {
+    let (first, last) = match bytes {
+        Some(bytes) => bytes,
+        None => return Ok(Response::with((status::Ok, path))),
+    };
+    let body = read_bytes(&path, first, last)
+        .map_err(|e| IronError::new(e, status::InternalServerError))?;
+    let mut res = Response::with((status::PartialContent, body));
+    res.headers.set(ContentRange(ContentRangeSpec::Bytes {
+        range: Some((first, last)),
+        instance_length: Some(size(&path)),
+    }));
+    Ok(res)
+}
+
+fn read_bytes(path: &Path, first: u64, last: u64) -> Result<Vec<u8>, io::Error> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(first))?;
+    let mut body = Vec::new();
+    file.take(last - first + 1).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn stats_view(sender: &StatsSender) -> IronResult<Response> {
+    let db = open_stats()?;
+    let stats = db.stats();
+    let mut res = Response::with((
+        status::Ok,
+        format!(
+            include_str!("stats.html"),
+            stats.downloads, stats.hits, stats.misses, stats.bandwidth_saved
+        ),
+        Mime(TopLevel::Text, SubLevel::Html, vec![]),
+    ));
+    mark_degraded(&mut res, sender);
+    Ok(res)
+}
+
+fn stats_json(sender: &StatsSender) -> IronResult<Response> {
+    let db = open_stats()?;
+    let stats = db.stats();
+    let mut res = Response::with((
+        status::Ok,
+        stats.as_json(),
+        Mime(TopLevel::Text, SubLevel::Json, vec![]),
+    ));
+    mark_degraded(&mut res, sender);
+    Ok(res)
+}
+
+/// Answers with a 503 rather than failing the request when the statistics
+/// database can't be opened.
+fn open_stats() -> IronResult<Database> {
+    Database::new(None::<&str>, None).map_err(|e| IronError::new(e, status::ServiceUnavailable))
+}
+
+/// Warns clients that the statistics miss requests the collector couldn't write.
+fn mark_degraded(res: &mut Response, sender: &StatsSender) {
+    if sender.is_degraded() {
+        res.headers.set_raw(
+            "Warning",
+            vec![b"199 cargo-cacher \"statistics database unavailable, figures are stale\""
+                .to_vec()],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_requests_are_served_and_recorded_partially() {
+        let range = |spec| Some(Range::Bytes(vec![spec]));
+        assert_eq!(requested_bytes(range(ByteRangeSpec::FromTo(0, 99)).as_ref(), 1000),
+                   Some((0, 99)));
+        assert_eq!(requested_bytes(range(ByteRangeSpec::FromTo(900, 1999)).as_ref(), 1000),
+                   Some((900, 999)));
+        assert_eq!(requested_bytes(range(ByteRangeSpec::AllFrom(600)).as_ref(), 1000),
+                   Some((600, 999)));
+        assert_eq!(requested_bytes(range(ByteRangeSpec::Last(2000)).as_ref(), 1000),
+                   Some((0, 999)));
+        assert_eq!(requested_bytes(range(ByteRangeSpec::AllFrom(1000)).as_ref(), 1000), None);
+        assert_eq!(requested_bytes(range(ByteRangeSpec::Last(0)).as_ref(), 1000), None);
+        assert_eq!(requested_bytes(Some(&Range::bytes_multi(vec![(0, 9), (20, 29)])), 1000),
+                   None);
+        assert_eq!(requested_bytes(None, 1000), None);
+
+        assert_eq!(served_size(Some((900, 999)), 1000), 100);
+        assert_eq!(served_size(None, 1000), 1000);
+    }
+}
@@ -1,13 +1,113 @@
-use std::sync::mpsc::{SyncSender, sync_channel};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::task::{Context, Poll};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::CargoRequest;
+use super::{CargoRequest, Config};
 
+use log::{self, Level, Record};
+use log::kv::ToValue;
 use rusqlite;
-use rusqlite::params;
+use serde_json;
+use humantime;
+use rusqlite::{params, OptionalExtension};
+use rusqlite::types::ValueRef;
+use semver::Version;
+use tokio;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const LOG_SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+const CHANNEL_CAPACITY: usize = 1024;
+/// Aggregated rows the collector holds before it flushes early; while the
+/// database can't be written, requests needing a new row are dropped instead.
+const MAX_PENDING_ROWS: usize = 65_536;
+const DROPPED_EVENTS: &str = "dropped_events";
+const STARTED_AT: &str = "started_at";
+const LAST_FLUSH_AT: &str = "last_flush_at";
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+const WATCHDOG_WINDOW: &str = "1 hour";
+const FORECAST_HISTORY_DAYS: usize = 28;
+const BYTES_PER_GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+const STATS_DB_ENV: &str = "CACHER_STATS_DB";
+const TABLE_PREFIX_ENV: &str = "CACHER_STATS_TABLE_PREFIX";
+/// Every table the schema creates, before the table prefix is applied.
+const TABLES: &[&str] = &["crates",
+                          "crate_versions",
+                          "downloads",
+                          "stats_snapshots",
+                          "collector_meta",
+                          "dependency_edges",
+                          "counters"];
+const UTC_OFFSET_ENV: &str = "CACHER_STATS_UTC_OFFSET";
+const AGGREGATE_ONLY_ENV: &str = "CACHER_STATS_AGGREGATE_ONLY";
+const CLIENT_SALT_ENV: &str = "CACHER_STATS_CLIENT_SALT";
+const DEFAULT_WINDOW: &str = "24 hours";
+const DIGEST_TOP_CRATES: usize = 5;
+/// Crates downloaded less often than this are too noisy for the digest's
+/// worst hit ratio.
+const DIGEST_MIN_DOWNLOADS: i64 = 5;
+/// Stored as `PRAGMA user_version` once `from_connection` has brought the
+/// schema up to date; bump it and extend `Database::migrate` whenever
+/// existing rows need rewriting. The version covers the whole file, so
+/// tables of a prefix created after it was stamped start out migrated.
+const SCHEMA_VERSION: i32 = 1;
+const COMMIT_LATENCY_WEIGHT: f64 = 0.2;
 
 pub struct Database {
     conn: rusqlite::Connection,
+    /// Prepended to every table and index name, ex: `prod_`
+    table_prefix: String,
+    /// Window used by the convenience variants of the reporting methods
+    default_window: String,
+    /// Stands in for `now` in every time-based query
+    clock: Box<dyn Clock>,
+    /// Shift applied when grouping by hour or day; storage stays in UTC
+    utc_offset_minutes: i32,
+    /// Only per-crate totals are kept in `counters`, never a row per request
+    aggregate_only: bool,
+    /// When set, clients are stored as their HMAC-SHA256 keyed with this salt
+    client_salt: Option<String>,
+}
+
+/// Source of the current time for `Database`, so windows can be tested
+/// against a frozen instant.
+pub trait Clock: Send {
+    /// The current time as an SQLite time value, ex: `2020-01-31 12:00:00`.
+    fn now(&self) -> String;
+}
+
+/// SQLite's own `now`; the default clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> String {
+        "now".to_string()
+    }
+}
+
+/// A clock stopped at a fixed time, ex: `FixedClock("2020-01-31 12:00:00".into())`.
+pub struct FixedClock(pub String);
+
+impl Clock for FixedClock {
+    fn now(&self) -> String {
+        self.0.clone()
+    }
 }
 
 #[derive(Debug)]
@@ -18,6 +118,133 @@ pub struct Statistics {
     pub bandwidth_saved: i64,
 }
 
+#[derive(Debug)]
+pub enum StatsError {
+    Sqlite(rusqlite::Error),
+    /// The arguments were rejected before touching the database.
+    Validation(String),
+    Io(io::Error),
+    /// An `AsyncDatabase` call panicked or its runtime shut down first.
+    Task(tokio::task::JoinError),
+    /// An `AsyncDatabase` call was made outside of a tokio runtime.
+    NoRuntime,
+}
+
+impl fmt::Display for StatsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StatsError::Sqlite(ref e) => write!(f, "stats database error: {}", e),
+            StatsError::Validation(ref msg) => write!(f, "invalid stats input: {}", msg),
+            StatsError::Io(ref e) => write!(f, "stats I/O error: {}", e),
+            StatsError::Task(ref e) => write!(f, "stats task failed: {}", e),
+            StatsError::NoRuntime => write!(f, "stats call made outside of a tokio runtime"),
+        }
+    }
+}
+
+impl Error for StatsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StatsError::Sqlite(ref e) => Some(e),
+            StatsError::Validation(_) => None,
+            StatsError::Io(ref e) => Some(e),
+            StatsError::Task(ref e) => Some(e),
+            StatsError::NoRuntime => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StatsError {
+    fn from(e: rusqlite::Error) -> StatsError {
+        StatsError::Sqlite(e)
+    }
+}
+
+impl From<io::Error> for StatsError {
+    fn from(e: io::Error) -> StatsError {
+        StatsError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crate {
+    pub name: String,
+    /// Number of known versions
+    pub versions: i64,
+    pub downloads: i64,
+}
+
+/// Per-version traffic of a crate, see `Database::version_breakdown`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionStats {
+    pub version: String,
+    pub downloads: i64,
+    pub hits: i64,
+    pub bytes_saved: i64,
+}
+
+#[derive(Debug)]
+pub struct Diagnostics {
+    pub sqlite_version: &'static str,
+    /// Database file, or ":memory:" for in-memory databases.
+    pub path: String,
+    pub user_version: i32,
+    pub table_rows: Vec<(String, i64)>,
+}
+
+/// A week's headline numbers, see `Database::weekly_digest`.
+#[derive(Debug)]
+pub struct WeeklyDigest {
+    /// Totals over the last 7 days
+    pub totals: Statistics,
+    /// Most downloaded crates of the week with their downloads
+    pub top_crates: Vec<(String, i64)>,
+    /// Crate whose hits saved the most bandwidth, with the bytes saved
+    pub biggest_saver: Option<(String, i64)>,
+    /// Crate with the lowest hit ratio among the frequently downloaded ones
+    pub worst_hit_ratio: Option<(String, f64)>,
+    /// This week's totals minus the week before's
+    pub downloads_delta: i64,
+    pub hits_delta: i64,
+    pub misses_delta: i64,
+    pub bandwidth_saved_delta: i64,
+}
+
+/// How much room the stats database takes, see `Database::db_footprint`.
+#[derive(Debug)]
+pub struct DbFootprint {
+    pub table_rows: Vec<(String, i64)>,
+    /// `page_count * page_size`: the file size of on-disk databases and the
+    /// memory taken by in-memory ones.
+    pub size_bytes: i64,
+}
+
+impl Serialize for Statistics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Statistics", 4)?;
+        state.serialize_field("downloads", &self.downloads)?;
+        state.serialize_field("hits", &self.hits)?;
+        state.serialize_field("misses", &self.misses)?;
+        state.serialize_field("bandwidth_saved", &self.bandwidth_saved)?;
+        state.end()
+    }
+}
+
+impl Serialize for WeeklyDigest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("WeeklyDigest", 8)?;
+        state.serialize_field("totals", &self.totals)?;
+        state.serialize_field("top_crates", &self.top_crates)?;
+        state.serialize_field("biggest_saver", &self.biggest_saver)?;
+        state.serialize_field("worst_hit_ratio", &self.worst_hit_ratio)?;
+        state.serialize_field("downloads_delta", &self.downloads_delta)?;
+        state.serialize_field("hits_delta", &self.hits_delta)?;
+        state.serialize_field("misses_delta", &self.misses_delta)?;
+        state.serialize_field("bandwidth_saved_delta", &self.bandwidth_saved_delta)?;
+        state.end()
+    }
+}
+
 impl Statistics {
     pub fn as_json(&self) -> String {
         json!({
@@ -27,62 +254,291 @@ impl Statistics {
             "bandwidth_saved": self.bandwidth_saved
         }).to_string()
     }
+
+    /// Prometheus text exposition body for a Pushgateway, grouped under `job`.
+    pub fn as_pushgateway(&self, job: &str) -> String {
+        let mut body = format!("# Pushgateway grouping: job=\"{}\"\n", job);
+        for &(name, value) in &[("downloads", self.downloads),
+                                ("hits", self.hits),
+                                ("misses", self.misses),
+                                ("bandwidth_saved_bytes", self.bandwidth_saved)] {
+            body.push_str(&format!("# TYPE cargo_cacher_{0} gauge\ncargo_cacher_{0} {1}\n",
+                                   name,
+                                   value));
+        }
+        body
+    }
 }
 
 impl Database {
-    pub fn new<T: Into<String>>(connection_string: Option<T>) -> Database {
+    /// Opens the stats database, namespacing its tables with `table_prefix`
+    /// so several deployments can share one database. Without one, the
+    /// prefix comes from `CACHER_STATS_TABLE_PREFIX`.
+    pub fn new<T: Into<String>>(connection_string: Option<T>,
+                                table_prefix: Option<&str>)
+                                -> Result<Database, StatsError> {
 
         let connection_string: String = if let Some(s) = connection_string {
             s.into()
+        } else if let Ok(s) = env::var(STATS_DB_ENV) {
+            s
         } else {
             "file::memory:?cache=shared".to_string()
             // "database.sqlite".into()
         };
-        let conn = rusqlite::Connection::open(&connection_string).unwrap();
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS crates (
+        let conn = rusqlite::Connection::open(&connection_string)?;
+        let table_prefix = table_prefix.map(String::from)
+            .or_else(|| env::var(TABLE_PREFIX_ENV).ok());
+        Database::from_connection(conn, table_prefix.as_deref())
+    }
+
+    /// Sets up the schema on an already opened connection, e.g. one configured
+    /// with custom pragmas.
+    pub fn from_connection(conn: rusqlite::Connection,
+                           table_prefix: Option<&str>)
+                           -> Result<Database, StatsError> {
+        let table_prefix = table_prefix.unwrap_or("").to_string();
+        if !table_prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(StatsError::Validation(format!("invalid table prefix {:?}", table_prefix)));
+        }
+        let db = Database {
+            conn,
+            table_prefix,
+            default_window: DEFAULT_WINDOW.to_string(),
+            clock: Box::new(SystemClock),
+            utc_offset_minutes: env::var(UTC_OFFSET_ENV)
+                .ok()
+                .and_then(|offset| {
+                    let minutes = parse_utc_offset(&offset);
+                    if minutes.is_none() {
+                        warn!("Ignoring {}={:?}, expected ex: +02:00", UTC_OFFSET_ENV, offset);
+                    }
+                    minutes
+                })
+                .unwrap_or(0),
+            aggregate_only: env::var(AGGREGATE_ONLY_ENV)
+                .map(|value| value == "1" || value == "true")
+                .unwrap_or(false),
+            client_salt: env::var(CLIENT_SALT_ENV).ok().filter(|salt| !salt.is_empty()),
+        };
+        let conn = &db.conn;
+        conn.execute(&db.sql("
+            CREATE TABLE IF NOT EXISTS {p}crates (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 name TEXT
-            );",
-                     params![])
-            .unwrap();
-        conn.execute("
-             CREATE TABLE IF NOT EXISTS crate_versions (
+            );"),
+                     params![])?;
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}crate_versions (
                  id INTEGER PRIMARY KEY AUTOINCREMENT,
                  version TEXT,
                  crate_id INTEGER
-             );",
-                     params![])
-            .unwrap();
-        conn.execute("
-             CREATE TABLE IF NOT EXISTS downloads (
+             );"),
+                     params![])?;
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}downloads (
                  version_id INTEGER,
                  time TIMESTAMP,
                  hit BOOLEAN,
                  size BIGINT
-             );",
-                     params![])
-            .unwrap();
+             );"),
+                     params![])?;
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}stats_snapshots (
+                 time TIMESTAMP,
+                 downloads BIGINT,
+                 hits BIGINT,
+                 misses BIGINT,
+                 bandwidth_saved BIGINT
+             );"),
+                     params![])?;
 
-        conn.execute("
-            CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_names
-            ON crates (name)",
-                     params![])
-            .unwrap();
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}collector_meta (
+                 key TEXT PRIMARY KEY,
+                 value TEXT
+             );"),
+                     params![])?;
 
-        conn.execute("
-            CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_versions
-            ON crate_versions (crate_id, version)",
-                     params![])
-            .unwrap();
-        Database { conn: conn }
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}dependency_edges (
+                 parent TEXT,
+                 child TEXT
+             );"),
+                     params![])?;
+
+        conn.execute(&db.sql("
+             CREATE TABLE IF NOT EXISTS {p}counters (
+                 crate_id INTEGER PRIMARY KEY,
+                 downloads BIGINT NOT NULL DEFAULT 0,
+                 hits BIGINT NOT NULL DEFAULT 0,
+                 misses BIGINT NOT NULL DEFAULT 0,
+                 bytes BIGINT NOT NULL DEFAULT 0
+             );"),
+                     params![])?;
+
+        let crates = db.sql("{p}crates");
+        let crate_versions = db.sql("{p}crate_versions");
+        let downloads = db.sql("{p}downloads");
+        add_column(conn, &crates, "first_seen", "TIMESTAMP")?;
+        add_column(conn, &crate_versions, "checksum", "TEXT")?;
+        add_column(conn, &crate_versions, "yanked", "BOOLEAN NOT NULL DEFAULT 0")?;
+        add_column(conn, &downloads, "count", "INTEGER NOT NULL DEFAULT 1")?;
+        add_column(conn, &downloads, "client", "TEXT")?;
+        add_column(conn, &downloads, "is_range", "BOOLEAN NOT NULL DEFAULT 0")?;
+        add_column(conn, &downloads, "bypass", "BOOLEAN NOT NULL DEFAULT 0")?;
+        add_column(conn, &downloads, "fetch_ms", "INTEGER")?;
+        add_column(conn, &downloads, "upstream_down", "BOOLEAN NOT NULL DEFAULT 0")?;
+        add_column(conn, &downloads, "failed", "BOOLEAN NOT NULL DEFAULT 0")?;
+        add_column(conn, &downloads, "target", "TEXT")?;
+        db.index_crate_names()?;
+
+        conn.execute(&db.sql("
+            CREATE UNIQUE INDEX IF NOT EXISTS {p}unique_crate_versions
+            ON {p}crate_versions (crate_id, version)"),
+                     params![])?;
+
+        conn.execute(&db.sql("
+            CREATE UNIQUE INDEX IF NOT EXISTS {p}unique_dependency_edges
+            ON {p}dependency_edges (parent, child)"),
+                     params![])?;
+
+        // Data migrations write, so they only run while the version is
+        // behind; opening an up-to-date database never needs a write lock.
+        let version = db.schema_version()?;
+        if version < SCHEMA_VERSION {
+            db.migrate(version)?;
+        }
+        Ok(db)
+    }
+
+    /// Brings the data of a database at schema version `from` up to
+    /// `SCHEMA_VERSION` in one transaction. The version is never lowered, so
+    /// an older build opening a newer database can't claim to have migrated it.
+    fn migrate(&self, from: i32) -> Result<(), StatsError> {
+        self.transaction(|| {
+            if from < 1 {
+                self.backfill_first_seen()?;
+            }
+            self.conn.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+            Ok(())
+        })
+    }
+
+    /// Crates from before `first_seen` was recorded were first seen with
+    /// their first download.
+    fn backfill_first_seen(&self) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute(&self.sql("UPDATE {p}crates SET first_seen = (
+                SELECT min(downloads.time)
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                WHERE crate_versions.crate_id = {p}crates.id
+            )
+            WHERE first_seen IS NULL"),
+                     params![])?;
+        Ok(())
+    }
+
+    /// Crate names are case-insensitive; older databases may still hold rows
+    /// differing only in case, which keeps the old index in place until
+    /// `dedupe_crates` merges them.
+    fn index_crate_names(&self) -> Result<(), rusqlite::Error> {
+        match self.conn.execute(&self.sql("
+            CREATE UNIQUE INDEX IF NOT EXISTS {p}unique_crate_names_nocase
+            ON {p}crates (name COLLATE NOCASE)"),
+                                params![]) {
+            Ok(_) => {
+                self.conn.execute(&self.sql("DROP INDEX IF EXISTS {p}unique_crate_names"),
+                                  params![])?;
+            }
+            Err(e) => {
+                warn!("Couldn't create case-insensitive crate name index: {}", e);
+                self.conn.execute(&self.sql("
+                    CREATE UNIQUE INDEX IF NOT EXISTS {p}unique_crate_names
+                    ON {p}crates (name)"),
+                                  params![])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands the `{p}` table prefix and `{tz}` UTC offset modifier
+    /// placeholders in a query and points its `'now'` at the clock.
+    fn sql(&self, query: &str) -> String {
+        let now = sql_literal(ValueRef::Text(self.clock.now().as_bytes()));
+        query.replace("{p}", &self.table_prefix)
+            .replace("{tz}", &format!("'{:+} minutes'", self.utc_offset_minutes))
+            .replace("'now'", &now)
+    }
+
+    /// Groups hourly and daily reports by local time `minutes` ahead of UTC,
+    /// ex: 120 for UTC+02:00.
+    pub fn set_utc_offset(&mut self, minutes: i32) {
+        self.utc_offset_minutes = minutes;
+    }
+
+    /// Replaces the clock every time-based query reads `now` from.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Keeps only per-crate totals instead of a row per request, for
+    /// deployments that must not retain who downloaded what and when. Windows
+    /// can't be applied to totals, so `stats_in` then covers all time and
+    /// the reports built on individual downloads come back empty.
+    pub fn set_aggregate_only(&mut self, aggregate_only: bool) {
+        self.aggregate_only = aggregate_only;
+    }
+
+    /// Stores an HMAC-SHA256 of each client keyed with the salt instead of the
+    /// client itself, so per-client reports still group requests without
+    /// revealing who made them. Clients recorded before the salt was set or
+    /// changed are kept as they were and group separately.
+    pub fn set_client_salt<T: Into<String>>(&mut self, salt: Option<T>) {
+        self.client_salt = salt.map(Into::into);
+    }
+
+    /// The client as it is stored, hashed when a client salt is set.
+    fn stored_client(&self, client: &Option<String>) -> Option<String> {
+        match self.client_salt {
+            Some(ref salt) => {
+                client.as_ref().map(|client| {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes())
+                        .expect("HMAC accepts keys of any length");
+                    mac.update(client.as_bytes());
+                    mac.finalize()
+                        .into_bytes()
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect()
+                })
+            }
+            None => client.clone(),
+        }
+    }
+
+    /// Changes the window `stats()` reports on, ex: `7 days`.
+    pub fn set_default_window<T: Into<String>>(&mut self, window: T) {
+        self.default_window = window.into();
     }
 
+    /// Statistics over the default window, 24 hours unless changed.
     pub fn stats(&self) -> Statistics {
-        let downloads = self.downloads("24 hours");
-        let hits = self.hits("24 hours");
-        let misses = downloads - hits;
-        let bandwidth_saved = self.bandwidth_saved("24 hours");
+        self.stats_in(&self.default_window)
+    }
+
+    /// Statistics over the given window.
+    pub fn stats_in(&self, window: &str) -> Statistics {
+        if self.aggregate_only {
+            return self.counter_stats();
+        }
+        let downloads = self.downloads(window);
+        let hits = self.hits(window);
+        let misses = downloads - hits - self.bypasses(window) as i32 -
+                     self.outage_misses(window) as i32;
+        let bandwidth_saved = self.bandwidth_saved(window);
         Statistics {
             downloads: downloads as i64,
             hits: hits as i64,
@@ -91,9 +547,148 @@ impl Database {
         }
     }
 
+    /// What `stats_in` would have reported had only the `n` most downloaded
+    /// crates of the window been cached: their hits count as before and every
+    /// request for another crate becomes a miss.
+    pub fn what_if_top_n(&self, n: usize, window: &str) -> Result<Statistics, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("WITH in_window AS (
+                SELECT downloads.*, crate_versions.crate_id
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                WHERE downloads.time > datetime('now', '-' || $1)
+            ), top AS (
+                SELECT crate_id FROM in_window
+                GROUP BY crate_id
+                ORDER BY sum(count) DESC, crate_id
+                LIMIT $2
+            ), hypothetical AS (
+                SELECT count, size, bypass, upstream_down,
+                       hit = 1 AND crate_id IN (SELECT crate_id FROM top) AS hit
+                FROM in_window
+            )
+            SELECT COALESCE(sum(count), 0),
+                   COALESCE(sum(CASE WHEN hit AND bypass = 0 THEN count ELSE 0 END), 0),
+                   COALESCE(sum(CASE WHEN hit OR bypass = 1 OR upstream_down = 1
+                                THEN 0 ELSE count END), 0),
+                   COALESCE(sum(CASE WHEN hit THEN size ELSE 0 END), 0)
+            FROM hypothetical"),
+                       params![window, n as i64],
+                       |row| {
+                Ok(Statistics {
+                    downloads: row.get(0)?,
+                    hits: row.get(1)?,
+                    misses: row.get(2)?,
+                    bandwidth_saved: row.get(3)?,
+                })
+            })?)
+    }
+
+    /// What `stats_in` reports, counting only requests for the crates in
+    /// `names`. Unknown names are ignored.
+    pub fn stats_for_crates(&self,
+                            names: &[String],
+                            window: &str)
+                            -> Result<Statistics, StatsError> {
+        let placeholders = (0..names.len())
+            .map(|i| format!("${}", i + 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&window];
+        params.extend(names.iter().map(|name| name as &dyn rusqlite::ToSql));
+        Ok(self.conn
+            .query_row(&self.sql(&format!("SELECT COALESCE(sum(downloads.count), 0),
+                   COALESCE(sum(CASE WHEN downloads.hit = 1 AND downloads.bypass = 0
+                                THEN downloads.count ELSE 0 END), 0),
+                   COALESCE(sum(CASE WHEN downloads.hit = 1 OR downloads.bypass = 1
+                                     OR downloads.upstream_down = 1
+                                THEN 0 ELSE downloads.count END), 0),
+                   COALESCE(sum(CASE WHEN downloads.hit = 1 THEN downloads.size ELSE 0 END), 0)
+            FROM {{p}}downloads AS downloads
+            INNER JOIN {{p}}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {{p}}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+                AND crates.name COLLATE NOCASE IN ({})",
+                                          placeholders)),
+                       params,
+                       |row| {
+                Ok(Statistics {
+                    downloads: row.get(0)?,
+                    hits: row.get(1)?,
+                    misses: row.get(2)?,
+                    bandwidth_saved: row.get(3)?,
+                })
+            })?)
+    }
+
+    /// Headline numbers of the last 7 days, compared with the 7 days before.
+    pub fn weekly_digest(&self) -> Result<WeeklyDigest, StatsError> {
+        let totals = self.stats_in("7 days");
+        let fortnight = self.stats_in("14 days");
+        let mut top_crates = self.crate_downloads("7 days")?;
+        top_crates.truncate(DIGEST_TOP_CRATES);
+        Ok(WeeklyDigest {
+            top_crates,
+            biggest_saver: self.crate_bytes_saved("7 days")?.into_iter().next(),
+            worst_hit_ratio: self.worst_hit_ratio_crates("7 days", DIGEST_MIN_DOWNLOADS, 1)?
+                .into_iter()
+                .next(),
+            downloads_delta: 2 * totals.downloads - fortnight.downloads,
+            hits_delta: 2 * totals.hits - fortnight.hits,
+            misses_delta: 2 * totals.misses - fortnight.misses,
+            bandwidth_saved_delta: 2 * totals.bandwidth_saved - fortnight.bandwidth_saved,
+            totals,
+        })
+    }
+
+    fn counter_stats(&self) -> Statistics {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(downloads), 0), COALESCE(sum(hits), 0),
+                   COALESCE(sum(misses), 0), COALESCE(sum(bytes), 0)
+            FROM {p}counters"),
+                       params![],
+                       |row| {
+                Ok(Statistics {
+                    downloads: row.get(0)?,
+                    hits: row.get(1)?,
+                    misses: row.get(2)?,
+                    bandwidth_saved: row.get(3)?,
+                })
+            })
+            .unwrap_or(Statistics {
+                downloads: 0,
+                hits: 0,
+                misses: 0,
+                bandwidth_saved: 0,
+            })
+    }
+
+    /// Adds to a crate's totals in `counters`.
+    fn add_counts(&self,
+                  crate_id: i32,
+                  downloads: i64,
+                  hits: i64,
+                  misses: i64,
+                  bytes: i64)
+                  -> Result<(), StatsError> {
+        self.conn
+            .execute(&self.sql("INSERT OR IGNORE INTO {p}counters (crate_id) VALUES ($1)"),
+                     params![crate_id])?;
+        self.conn
+            .execute(&self.sql("UPDATE {p}counters
+            SET downloads = downloads + $1, hits = hits + $2, misses = misses + $3,
+                bytes = bytes + $4
+            WHERE crate_id = $5"),
+                     params![downloads, hits, misses, bytes, crate_id])?;
+        Ok(())
+    }
+
     pub fn downloads<T: Into<String>>(&self, time: T) -> i32 {
         let mut stmt = self.conn
-            .prepare("SELECT count(*) FROM downloads WHERE time > date('now') - $1")
+            .prepare(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads \
+                      WHERE time > datetime('now', '-' || $1)"))
             .unwrap();
         let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
             Ok(s) => s,
@@ -109,7 +704,8 @@ impl Database {
 
     pub fn hits<T: Into<String>>(&self, time: T) -> i32 {
         let mut stmt = self.conn
-            .prepare("SELECT count(*) FROM downloads WHERE time > date('now') - $1 AND hit = 1")
+            .prepare(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads \
+                      WHERE time > datetime('now', '-' || $1) AND hit = 1 AND bypass = 0"))
             .unwrap();
         let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
             Ok(s) => s,
@@ -125,8 +721,9 @@ impl Database {
 
     pub fn bandwidth_saved<T: Into<String>>(&self, time: T) -> i64 {
         let mut stmt = self.conn
-            .prepare("SELECT COALESCE(sum(size), 0) FROM downloads WHERE time > date('now') - $1 \
-                      AND hit = 1")
+            .prepare(&self.sql("SELECT COALESCE(sum(size), 0) FROM {p}downloads \
+                      WHERE time > datetime('now', '-' || $1) \
+                      AND hit = 1"))
             .unwrap();
         let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
             Ok(s) => s,
@@ -140,73 +737,4808 @@ impl Database {
         0
     }
 
-    fn crate_id<T: Into<String>>(&self, name: T) -> Option<i32> {
-        let mut stmt = self.conn.prepare("SELECT id FROM crates WHERE name = $1").unwrap();
-        let rows = stmt.query_map(&[&name.into()], |row| row.get(0)).unwrap();
-        for record in rows {
-            if let Ok(id) = record {
-                return Some(id);
+    /// Bytes served from the cache since stats began.
+    pub fn bandwidth_saved_total(&self) -> i64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(size), 0) FROM {p}downloads WHERE hit = 1"),
+                       params![],
+                       |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    /// Bytes of the cached crate files, taking each version's size from its
+    /// largest full (non-range) download.
+    pub fn cached_bytes(&self) -> i64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(size), 0) FROM (
+                SELECT max(size / count) AS size FROM {p}downloads
+                WHERE is_range = 0
+                GROUP BY version_id
+            )"),
+                       params![],
+                       |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    /// How many times over each cached byte has been served from the cache,
+    /// 0 when nothing is cached.
+    pub fn cache_leverage(&self) -> f64 {
+        let cached = self.cached_bytes();
+        if cached == 0 {
+            return 0.0;
+        }
+        self.bandwidth_saved_total() as f64 / cached as f64
+    }
+
+    /// Average bytes saved by each cache hit within the window, 0 without hits.
+    /// Bypassed requests count neither as hits nor as savings.
+    pub fn avg_bytes_saved_per_hit(&self, window: &str) -> f64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(CAST(sum(size) AS REAL) / sum(count), 0)
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1) AND hit = 1 AND bypass = 0"),
+                       params![window],
+                       |row| row.get(0))
+            .unwrap_or(0.0)
+    }
+
+    /// Egress cost avoided by cache hits within the window, at `price_per_gb`
+    /// per GiB (1 GiB = 1024^3 bytes).
+    pub fn estimated_cost_saved(&self, window: &str, price_per_gb: f64) -> f64 {
+        self.bandwidth_saved(window) as f64 / BYTES_PER_GIB * price_per_gb
+    }
+
+    /// Projected bandwidth saved over the next `horizon_days`, from a
+    /// least-squares line fitted to the daily savings of the last
+    /// `FORECAST_HISTORY_DAYS` complete days. Days without hits count as zero
+    /// and projected days never go below zero.
+    pub fn forecast_bandwidth_saved(&self, horizon_days: u32) -> i64 {
+        let mut daily = vec![0.0; FORECAST_HISTORY_DAYS];
+        let mut stmt = match self.conn
+            .prepare(&self.sql("SELECT
+                   CAST(julianday(date('now', {tz})) - julianday(date(time, {tz})) AS INTEGER)
+                       AS age,
+                   sum(size)
+            FROM {p}downloads
+            WHERE hit = 1 AND date(time, {tz}) >= date('now', {tz}, '-' || $1 || ' days')
+                AND date(time, {tz}) < date('now', {tz})
+            GROUP BY age")) {
+            Ok(stmt) => stmt,
+            Err(_) => return 0,
+        };
+        let rows = match stmt.query_map(params![FORECAST_HISTORY_DAYS as i64],
+                                        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))) {
+            Ok(rows) => rows,
+            Err(_) => return 0,
+        };
+        for (age, saved) in rows.flatten() {
+            if age >= 1 && age as usize <= FORECAST_HISTORY_DAYS {
+                daily[FORECAST_HISTORY_DAYS - age as usize] = saved as f64;
             }
         }
-        return None;
+
+        let n = FORECAST_HISTORY_DAYS as f64;
+        let mean_x = (n - 1.0) / 2.0;
+        let mean_y = daily.iter().sum::<f64>() / n;
+        let (mut covariance, mut variance) = (0.0, 0.0);
+        for (x, y) in daily.iter().enumerate() {
+            covariance += (x as f64 - mean_x) * (y - mean_y);
+            variance += (x as f64 - mean_x).powi(2);
+        }
+        let slope = covariance / variance;
+        (0..horizon_days)
+            .map(|day| mean_y + slope * (n + day as f64 - mean_x))
+            .map(|saved| saved.max(0.0))
+            .sum::<f64>()
+            .round() as i64
     }
 
-    fn version_id<T: Into<String>>(&self, crate_id: i32, version: T) -> Option<i32> {
+    /// Coefficient of variation (population standard deviation over mean) of
+    /// the daily downloads of the last `window_days` complete days, counting
+    /// days without downloads as zero. 0 when nothing was downloaded.
+    pub fn daily_traffic_cv(&self, window_days: u32) -> Result<f64, StatsError> {
+        if window_days == 0 {
+            return Err(StatsError::Validation("window must cover at least one day".into()));
+        }
+        let mut daily = vec![0.0; window_days as usize];
         let mut stmt = self.conn
-            .prepare("SELECT id
-            FROM crate_versions
-            WHERE crate_id = $1 \
-                      AND version = $2")
-            .unwrap();
-        let rows = stmt.query_map(params![crate_id, version.into()], |row| row.get(0)).unwrap();
-        for record in rows {
-            if let Ok(id) = record {
-                return Some(id);
+            .prepare(&self.sql("SELECT
+                   CAST(julianday(date('now', {tz})) - julianday(date(time, {tz})) AS INTEGER)
+                       AS age,
+                   sum(count)
+            FROM {p}downloads
+            WHERE date(time, {tz}) >= date('now', {tz}, '-' || $1 || ' days')
+                AND date(time, {tz}) < date('now', {tz})
+            GROUP BY age"))?;
+        let rows = stmt.query_map(params![window_days],
+                                  |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+        for row in rows {
+            let (age, count) = row?;
+            if age >= 1 && age <= i64::from(window_days) {
+                daily[age as usize - 1] = count as f64;
             }
         }
-        return None;
+        let n = f64::from(window_days);
+        let mean = daily.iter().sum::<f64>() / n;
+        if mean == 0.0 {
+            return Ok(0.0);
+        }
+        let variance = daily.iter().map(|count| (count - mean).powi(2)).sum::<f64>() / n;
+        Ok(variance.sqrt() / mean)
     }
 
-    pub fn add_request<T: Into<String>, S: Into<String>>(&self,
-                                                         crate_name: T,
-                                                         crate_version: S,
-                                                         hit: bool,
-                                                         size: i64)
-                                                         -> Result<(), rusqlite::Error> {
-        let crate_name = crate_name.into();
-        let crate_version = crate_version.into();
-        let _ = self.conn
-            .execute("INSERT OR IGNORE INTO crates (name) VALUES ($1)",
-                     params![crate_name])
-            .unwrap();
-        let crate_id = self.crate_id(crate_name).unwrap();
-        let _ = self.conn
-            .execute("INSERT OR IGNORE INTO crate_versions (crate_id, version) VALUES ($1, $2)",
-                     params![crate_id, crate_version])
-            .unwrap();
-        let version_id = self.version_id(crate_id, crate_version).unwrap();
+    /// Every crate in the catalog, by name.
+    pub fn crates(&self) -> Result<Vec<Crate>, StatsError> {
+        Ok(self.crates_with_activity()?.into_iter().map(|(krate, _)| krate).collect())
+    }
 
-        info!("Version ID: {}", version_id);
-        let _ = self.conn
-            .execute("INSERT INTO downloads (version_id, time, hit, size) VALUES ($1, \
-                      date('now'), $2, $3)",
-                     params![version_id, hit, size]);
+    /// Every crate with the age in seconds of its most recent download, or
+    /// `None` if it was cached without ever being downloaded.
+    pub fn crates_with_activity(&self) -> Result<Vec<(Crate, Option<i64>)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name,
+                count(DISTINCT crate_versions.id),
+                COALESCE(sum(downloads.count), 0),
+                CAST(round((julianday('now') - julianday(max(downloads.time))) * 86400) AS INTEGER)
+            FROM {p}crates AS crates
+            LEFT JOIN {p}crate_versions AS crate_versions ON crate_versions.crate_id = crates.id
+            LEFT JOIN {p}downloads AS downloads ON downloads.version_id = crate_versions.id
+            GROUP BY crates.id
+            ORDER BY crates.name"))?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((Crate {
+                    name: row.get(0)?,
+                    versions: row.get(1)?,
+                    downloads: row.get(2)?,
+                },
+                row.get(3)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Number of crates per uppercased first letter, with every non-alphabetic
+    /// first character counted under `#`.
+    pub fn crate_letter_index(&self) -> Result<Vec<(char, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT substr(name, 1, 1) AS letter, count(*)
+            FROM {p}crates
+            GROUP BY letter"))?;
+        let rows = stmt.query_map(params![], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut index = BTreeMap::new();
+        for (letter, count) in rows {
+            let letter = match letter.chars().next() {
+                Some(c) if c.is_alphabetic() => c.to_uppercase().next().unwrap_or(c),
+                _ => '#',
+            };
+            *index.entry(letter).or_insert(0) += count;
+        }
+        Ok(index.into_iter().collect())
+    }
+
+    /// Persists the current `stats()` so trends survive pruning of raw downloads.
+    pub fn snapshot(&self) -> Result<(), StatsError> {
+        let stats = self.stats();
+        self.conn
+            .execute(&self.sql("INSERT INTO {p}stats_snapshots \
+                      (time, downloads, hits, misses, bandwidth_saved) \
+                      VALUES (datetime('now'), $1, $2, $3, $4)"),
+                     params![stats.downloads, stats.hits, stats.misses, stats.bandwidth_saved])?;
         Ok(())
+    }
 
+    /// Snapshots taken between `start` and `end` (inclusive), oldest first.
+    pub fn snapshots_between(&self,
+                             start: &str,
+                             end: &str)
+                             -> Result<Vec<(String, Statistics)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT time, downloads, hits, misses, bandwidth_saved
+            FROM {p}stats_snapshots
+            WHERE time >= $1 AND time <= $2
+            ORDER BY time"))?;
+        let rows = stmt.query_map(params![start, end], |row| {
+            Ok((row.get(0)?,
+                Statistics {
+                    downloads: row.get(1)?,
+                    hits: row.get(2)?,
+                    misses: row.get(3)?,
+                    bandwidth_saved: row.get(4)?,
+                }))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn range_requests(&self, window: &str) -> i64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads \
+                        WHERE time > datetime('now', '-' || $1) AND is_range = 1"),
+                       params![window],
+                       |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    /// Time of the oldest recorded download, `None` if nothing was recorded.
+    pub fn earliest_download(&self) -> Result<Option<String>, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT min(time) FROM {p}downloads"),
+                       params![],
+                       |row| row.get(0))?)
+    }
+
+    /// Downloads within the window of crates at least `min` and, if given,
+    /// less than `max` bytes large.
+    pub fn downloads_in_size_range(&self,
+                                   min: i64,
+                                   max: Option<i64>,
+                                   window: &str)
+                                   -> Result<i64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads
+            WHERE size / count >= $1 AND ($2 IS NULL OR size / count < $2)
+                AND time > datetime('now', '-' || $3)"),
+                       params![min, max, window],
+                       |row| row.get(0))?)
+    }
+
+    /// Requests that deliberately bypassed the cache. They count as downloads
+    /// but neither as hits nor as misses.
+    pub fn bypasses(&self, window: &str) -> i64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads \
+                        WHERE time > datetime('now', '-' || $1) AND bypass = 1"),
+                       params![window],
+                       |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    /// Misses that couldn't be served because upstream was unreachable. Like
+    /// bypasses they count as downloads but not as misses of a cold cache.
+    pub fn outage_misses(&self, window: &str) -> i64 {
+        self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(count), 0) FROM {p}downloads \
+                        WHERE time > datetime('now', '-' || $1) \
+                        AND upstream_down = 1 AND bypass = 0"),
+                       params![window],
+                       |row| row.get(0))
+            .unwrap_or(0)
+    }
+
+    /// Downloads per downloaded crate per day over the last `window_days`
+    /// days, 0 when no crate was downloaded.
+    pub fn avg_requests_per_crate_per_day(&self, window_days: u32) -> f64 {
+        if window_days == 0 {
+            return 0.0;
+        }
+        let (downloads, crates): (i64, i64) = match self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(downloads.count), 0),
+                   count(DISTINCT crate_versions.crate_id)
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            WHERE downloads.time > datetime('now', '-' || $1 || ' days')"),
+                       params![window_days],
+                       |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(totals) => totals,
+            Err(_) => return 0.0,
+        };
+        if crates == 0 {
+            return 0.0;
+        }
+        downloads as f64 / crates as f64 / f64::from(window_days)
+    }
+
+    /// Bytes served per minute over the last `window_minutes` minutes.
+    pub fn byte_rate(&self, window_minutes: u32) -> f64 {
+        if window_minutes == 0 {
+            return 0.0;
+        }
+        let bytes: i64 = self.conn
+            .query_row(&self.sql("SELECT COALESCE(sum(size), 0) FROM {p}downloads \
+                        WHERE time > datetime('now', '-' || $1 || ' minutes')"),
+                       params![window_minutes],
+                       |row| row.get(0))
+            .unwrap_or(0);
+        bytes as f64 / f64::from(window_minutes)
+    }
+
+    /// Cached versions per known crate, 0 when there are no crates.
+    pub fn avg_versions_per_crate(&self) -> f64 {
+        self.conn
+            .query_row(&self.sql("SELECT CAST((SELECT count(*) FROM {p}crate_versions) AS REAL)
+                / (SELECT count(*) FROM {p}crates)"),
+                       params![],
+                       |row| row.get::<_, Option<f64>>(0))
+            .ok()
+            .flatten()
+            .unwrap_or(0.0)
+    }
+
+    /// Crates whose downloads over the last 7 days fell more than `threshold_pct`
+    /// percent below the 7 days before that, as `(name, previous_week, this_week)`.
+    pub fn declining_crates(&self,
+                            threshold_pct: f64)
+                            -> Result<Vec<(String, i64, i64)>, StatsError> {
+        if !(0.0..=100.0).contains(&threshold_pct) {
+            return Err(StatsError::Validation(format!("threshold of {}% is not a percentage",
+                                                      threshold_pct)));
+        }
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name,
+                SUM(CASE WHEN downloads.time <= datetime('now', '-7 days') THEN count ELSE 0 END)
+                    AS previous,
+                SUM(CASE WHEN downloads.time > datetime('now', '-7 days') THEN count ELSE 0 END)
+                    AS current
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-14 days')
+            GROUP BY crates.id
+            HAVING previous > 0 AND current < previous * (1.0 - $1 / 100.0)
+            ORDER BY previous - current DESC, crates.name"))?;
+        let rows = stmt.query_map(params![threshold_pct],
+                                  |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Crates with more downloads in the recent half of the window than in the
+    /// earlier half, scored by recent / earlier and highest first. Crates
+    /// absent from the earlier half score `f64::INFINITY`, rising the most,
+    /// and rank among themselves by their recent downloads.
+    pub fn trending_crates(&self,
+                           window: &str,
+                           limit: usize)
+                           -> Result<Vec<(String, f64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT name,
+                CASE WHEN earlier = 0 THEN 9e999 ELSE CAST(recent AS REAL) / earlier END AS score
+            FROM (
+                SELECT crates.name AS name,
+                    SUM(CASE WHEN downloads.time > midpoint THEN count ELSE 0 END) AS recent,
+                    SUM(CASE WHEN downloads.time <= midpoint THEN count ELSE 0 END) AS earlier
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+                INNER JOIN (
+                    SELECT datetime((julianday('now') + julianday('now', '-' || $1)) / 2)
+                        AS midpoint
+                )
+                WHERE downloads.time > datetime('now', '-' || $1)
+                GROUP BY crates.id
+            )
+            WHERE recent > earlier
+            ORDER BY score DESC, recent DESC, name
+            LIMIT $2"))?;
+        let rows = stmt.query_map(params![window, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Fraction of the crates requested in the `period` before the last one
+    /// that were requested again within the last `period`, ex: `90 days`;
+    /// 0 when nothing was requested in the earlier period.
+    pub fn crate_retention(&self, period: &str) -> Result<f64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT COALESCE(avg(retained), 0.0) FROM (
+                SELECT max(downloads.time > datetime('now', '-' || $1)) AS retained
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                WHERE downloads.time > datetime('now', '-' || $1, '-' || $1)
+                GROUP BY crate_versions.crate_id
+                HAVING max(downloads.time <= datetime('now', '-' || $1)) = 1
+            )"),
+                       params![period],
+                       |row| row.get(0))?)
+    }
+
+    /// Crates requested on the most distinct days within the window, with
+    /// their day count, so steady daily use outranks a single burst.
+    pub fn stickiest_crates(&self,
+                            window: &str,
+                            limit: usize)
+                            -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name,
+                count(DISTINCT date(downloads.time, {tz})) AS days
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+            GROUP BY crates.id
+            ORDER BY days DESC, crates.name
+            LIMIT $2"))?;
+        let rows = stmt.query_map(params![window, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Crates with the largest average download within the window, in bytes.
+    /// Range and failed requests don't reflect a crate's size and are left out.
+    pub fn fattest_crates(&self,
+                          window: &str,
+                          limit: usize)
+                          -> Result<Vec<(String, f64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name,
+                CAST(sum(downloads.size) AS REAL) / sum(downloads.count) AS avg_size
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+                AND downloads.is_range = 0 AND downloads.failed = 0
+            GROUP BY crates.id
+            HAVING sum(downloads.count) > 0
+            ORDER BY avg_size DESC, crates.name
+            LIMIT $2"))?;
+        let rows = stmt.query_map(params![window, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// The hour bucket with the most downloads in the window and its count.
+    /// Returns `QueryReturnedNoRows` when nothing was downloaded.
+    pub fn peak_hour(&self, window: &str) -> Result<(String, i64), StatsError> {
+        self.conn
+            .query_row(&self.sql("SELECT strftime('%Y-%m-%d %H:00:00', time, {tz}) AS hour,
+                   sum(count) AS total
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1)
+            GROUP BY hour
+            ORDER BY total DESC, hour DESC
+            LIMIT 1"),
+                       params![window],
+                       |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(StatsError::from)
+    }
+
+    /// Downloads in the window by hour of the day, 0 to 23.
+    pub fn hourly_histogram(&self, window: &str) -> Result<Vec<(u32, i64)>, StatsError> {
+        let mut histogram = (0..24).map(|hour| (hour, 0)).collect::<Vec<_>>();
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT CAST(strftime('%H', time, {tz}) AS INTEGER) AS hour,
+                   sum(count)
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1)
+            GROUP BY hour"))?;
+        let rows = stmt.query_map(params![window], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (hour, count) = row?;
+            histogram[hour as usize].1 = count;
+        }
+        Ok(histogram)
+    }
+
+    /// Hits and misses within the window per hour that saw either, as
+    /// `(hour, hits, misses)` in time order, counted the same way as `stats_in`.
+    pub fn hit_miss_hourly(&self, window: &str) -> Result<Vec<(String, i64, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT strftime('%Y-%m-%d %H:00:00', time, {tz}) AS hour,
+                   sum(CASE WHEN hit = 1 THEN count ELSE 0 END),
+                   sum(CASE WHEN hit = 0 AND upstream_down = 0 THEN count ELSE 0 END)
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1) AND bypass = 0
+            GROUP BY hour
+            ORDER BY hour"))?;
+        let rows = stmt.query_map(params![window],
+                                  |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Running total of bytes saved by cache hits within the window, one entry
+    /// per `bucket` (`minute`, `hour`, `day` or `month`) that saw any hits.
+    pub fn cumulative_bandwidth_series(&self,
+                                       window: &str,
+                                       bucket: &str)
+                                       -> Result<Vec<(String, i64)>, StatsError> {
+        let format = bucket_format(bucket)?;
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT strftime($1, time, {tz}) AS bucket,
+                   sum(sum(size)) OVER (ORDER BY strftime($1, time, {tz}))
+            FROM {p}downloads
+            WHERE hit = 1 AND time > datetime('now', '-' || $2)
+            GROUP BY bucket
+            ORDER BY bucket"))?;
+        let rows = stmt.query_map(params![format, window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Number of crates seen as of each `bucket` (`minute`, `hour`, `day` or
+    /// `month`) within the window in which new crates were first seen.
+    pub fn cumulative_distinct_crates(&self,
+                                      window: &str,
+                                      bucket: &str)
+                                      -> Result<Vec<(String, i64)>, StatsError> {
+        let format = bucket_format(bucket)?;
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT strftime($1, first_seen, {tz}) AS bucket,
+                   (SELECT count(*) FROM {p}crates
+                    WHERE first_seen <= datetime('now', '-' || $2))
+                   + sum(count(*)) OVER (ORDER BY strftime($1, first_seen, {tz}))
+            FROM {p}crates
+            WHERE first_seen > datetime('now', '-' || $2)
+            GROUP BY bucket
+            ORDER BY bucket"))?;
+        let rows = stmt.query_map(params![format, window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Share of requests in each `bucket` of the window that couldn't be
+    /// served, oldest bucket first; buckets without requests are left out.
+    pub fn failure_rate_series(&self,
+                               window: &str,
+                               bucket: &str)
+                               -> Result<Vec<(String, f64)>, StatsError> {
+        let format = bucket_format(bucket)?;
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT strftime($1, time, {tz}) AS bucket,
+                   CAST(sum(CASE WHEN failed = 1 THEN count ELSE 0 END) AS REAL) / sum(count)
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $2)
+            GROUP BY bucket
+            HAVING sum(count) > 0
+            ORDER BY bucket"))?;
+        let rows = stmt.query_map(params![format, window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Fraction of known crates that have been served from the cache at least
+    /// once, 0 when no crate is known.
+    pub fn crate_hit_coverage(&self) -> Result<f64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT COALESCE(CAST((
+                SELECT count(*) FROM (
+                    SELECT crate_versions.crate_id
+                    FROM {p}downloads AS downloads
+                    INNER JOIN {p}crate_versions AS crate_versions
+                        ON crate_versions.id = downloads.version_id
+                    WHERE downloads.hit = 1 AND downloads.bypass = 0
+                    UNION
+                    SELECT crate_id FROM {p}counters WHERE hits > 0
+                )
+            ) AS REAL) / (SELECT count(*) FROM {p}crates), 0.0)"),
+                       params![],
+                       |row| row.get(0))?)
+    }
+
+    /// Number of crates the stats know of.
+    pub fn distinct_crates(&self) -> Result<i64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT count(*) FROM {p}crates"), params![], |row| row.get(0))?)
+    }
+
+    /// Gini coefficient of per-crate downloads in the window: 0 when every
+    /// crate is equally popular, approaching 1 when a few crates dominate.
+    pub fn popularity_gini(&self, window: &str) -> f64 {
+        let mut counts = match self.crate_downloads(window) {
+            Ok(counts) => counts.into_iter().map(|(_, count)| count as f64).collect::<Vec<_>>(),
+            Err(_) => return 0.0,
+        };
+        let total: f64 = counts.iter().sum();
+        if total == 0.0 {
+            return 0.0;
+        }
+        counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = counts.len() as f64;
+        let weighted: f64 = counts.iter()
+            .enumerate()
+            .map(|(i, count)| (i + 1) as f64 * count)
+            .sum();
+        2.0 * weighted / (n * total) - (n + 1.0) / n
+    }
+
+    /// Herfindahl-Hirschman index of the clients' shares of the window's
+    /// downloads: 1 when a single client made them all, near 0 when many
+    /// clients share them evenly. Requests without a client are ignored.
+    pub fn client_concentration_hhi(&self, window: &str) -> Result<f64, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT sum(count) FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1) AND client IS NOT NULL
+            GROUP BY client"))?;
+        let counts = stmt.query_map(params![window], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        let total: i64 = counts.iter().sum();
+        if total == 0 {
+            return Ok(0.0);
+        }
+        Ok(counts.iter()
+            .map(|&count| {
+                let share = count as f64 / total as f64;
+                share * share
+            })
+            .sum())
     }
-}
 
+    /// Share of the window's downloads that went to the `k` most downloaded
+    /// crates, from 0 to 1.
+    pub fn top_k_share(&self, k: usize, window: &str) -> Result<f64, StatsError> {
+        let counts = self.crate_downloads(window)?;
+        let total: i64 = counts.iter().map(|&(_, count)| count).sum();
+        if total == 0 {
+            return Ok(0.0);
+        }
+        let top: i64 = counts.iter().take(k).map(|&(_, count)| count).sum();
+        Ok(top as f64 / total as f64)
+    }
 
-pub fn stat_collector() -> SyncSender<CargoRequest> {
-    let (sender, receiver) = sync_channel::<CargoRequest>(10);
-    let db = Database::new(None::<&str>);
-    thread::spawn(move || loop {
-        if let Ok(req) = receiver.recv() {
-            info!("Logging a crate request to sqlite: {:?}", req);
-            let _ = db.add_request(req.name, req.version, req.hit, req.size).unwrap();
+    /// Median gap in seconds between consecutive recorded requests for a
+    /// crate within the window, or `None` with fewer than two requests.
+    /// Requests aggregated into one row by the collector count as one arrival.
+    pub fn inter_arrival_median(&self,
+                                name: &str,
+                                window: &str)
+                                -> Result<Option<f64>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT julianday(downloads.time)
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE AND downloads.time > datetime('now', '-' || $2)
+            ORDER BY downloads.time"))?;
+        let times = stmt.query_map(params![name, window], |row| row.get::<_, f64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut gaps = times.windows(2)
+            .map(|pair| (pair[1] - pair[0]) * 86_400.0)
+            .collect::<Vec<_>>();
+        if gaps.is_empty() {
+            return Ok(None);
+        }
+        gaps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mid = gaps.len() / 2;
+        if gaps.len() % 2 == 0 {
+            Ok(Some((gaps[mid - 1] + gaps[mid]) / 2.0))
         } else {
-            break;
+            Ok(Some(gaps[mid]))
         }
-    });
-    sender
+    }
+
+    /// Median size of the crates downloaded within the window, or `None`
+    /// without downloads. Requests aggregated into one row count once.
+    pub fn median_crate_size(&self, window: &str) -> Result<Option<i64>, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT CAST(avg(size) AS INTEGER) FROM (
+                SELECT size / count AS size FROM {p}downloads
+                WHERE time > datetime('now', '-' || $1)
+                ORDER BY size
+                LIMIT 2 - (SELECT count(*) FROM {p}downloads
+                           WHERE time > datetime('now', '-' || $1)) % 2
+                OFFSET ((SELECT count(*) FROM {p}downloads
+                         WHERE time > datetime('now', '-' || $1)) - 1) / 2
+            )"),
+                       params![window],
+                       |row| row.get(0))?)
+    }
+
+    /// Fraction of the bytes served within the window that came from the
+    /// largest `percent` of requests, 0 when nothing was served.
+    pub fn top_percent_byte_share(&self, percent: f64, window: &str) -> Result<f64, StatsError> {
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(StatsError::Validation(format!("percent must be in (0, 100], got {}",
+                                                      percent)));
+        }
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT size / count AS each_size, count FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1) AND count > 0
+            ORDER BY each_size DESC"))?;
+        let rows = stmt.query_map(params![window], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(i64, i64)>, _>>()?;
+        let requests: i64 = rows.iter().map(|&(_, count)| count).sum();
+        let total: i64 = rows.iter().map(|&(size, count)| size * count).sum();
+        if total == 0 {
+            return Ok(0.0);
+        }
+        let mut remaining = (requests as f64 * percent / 100.0).ceil() as i64;
+        let mut top = 0;
+        for (size, count) in rows {
+            if remaining == 0 {
+                break;
+            }
+            let taken = cmp::min(count, remaining);
+            top += size * taken;
+            remaining -= taken;
+        }
+        Ok(top as f64 / total as f64)
+    }
+
+    /// Longest run of consecutive cache hits within the window, in time order.
+    /// Bypasses neither extend nor break a run. The collector folds a crate
+    /// version's repeated hits into one row stamped with the first of them,
+    /// so hits of one crate around another crate's miss may count as one run.
+    pub fn longest_hit_streak(&self, window: &str) -> Result<i64, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT hit, count FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1) AND bypass = 0
+            ORDER BY time, rowid"))?;
+        let rows = stmt.query_map(params![window], |row| {
+            Ok((row.get::<_, bool>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let (mut longest, mut current) = (0, 0);
+        for row in rows {
+            let (hit, count) = row?;
+            current = if hit { current + count } else { 0 };
+            longest = cmp::max(longest, current);
+        }
+        Ok(longest)
+    }
+
+    /// Seconds from a crate's first miss until the first hit after it, or
+    /// `None` if it never hit since.
+    pub fn first_hit_delay(&self, name: &str) -> Result<Option<f64>, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("WITH crate_downloads AS (
+                SELECT downloads.time, downloads.hit
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+                WHERE crates.name = $1 COLLATE NOCASE AND downloads.bypass = 0
+            ), first_miss AS (
+                SELECT min(time) AS time FROM crate_downloads WHERE hit = 0
+            )
+            SELECT (julianday(min(crate_downloads.time)) - julianday(first_miss.time)) * 86400
+            FROM crate_downloads, first_miss
+            WHERE crate_downloads.hit = 1 AND crate_downloads.time > first_miss.time"),
+                       params![name],
+                       |row| row.get(0))?)
+    }
+
+    /// Distinct versions of `name` requested so far today, in local time when
+    /// a UTC offset is set, ordered like `sorted_versions`.
+    pub fn versions_today(&self, name: &str) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT DISTINCT crate_versions.version
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE
+                AND date(downloads.time, {tz}) = date('now', {tz})"))?;
+        let mut versions = stmt.query_map(params![name], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        versions.sort_by(|a, b| cmp_versions(a, b));
+        Ok(versions)
+    }
+
+    /// Fraction of the versions that missed within the window which were hit
+    /// no later than `within` after one of those misses, ex: `"1 hour"`;
+    /// 0 without misses.
+    pub fn warm_conversion_rate(&self, within: &str, window: &str) -> Result<f64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT COALESCE(avg(converted), 0.0) FROM (
+                SELECT max(EXISTS (
+                    SELECT 1 FROM {p}downloads AS hits
+                    WHERE hits.version_id = misses.version_id AND hits.hit = 1
+                        AND hits.time > misses.time
+                        AND hits.time <= strftime('%Y-%m-%d %H:%M:%f', misses.time, '+' || $1)
+                )) AS converted
+                FROM {p}downloads AS misses
+                WHERE misses.time > datetime('now', '-' || $2) AND misses.hit = 0
+                    AND misses.bypass = 0 AND misses.upstream_down = 0 AND misses.failed = 0
+                GROUP BY misses.version_id
+            )"),
+                       params![within, window],
+                       |row| row.get(0))?)
+    }
+
+    /// The `limit` least recently downloaded versions as `(name, version)`,
+    /// oldest first; versions never downloaded come before all others.
+    pub fn lru_crates(&self, limit: usize) -> Result<Vec<(String, String)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            LEFT JOIN {p}downloads AS downloads ON downloads.version_id = crate_versions.id
+            GROUP BY crate_versions.id
+            ORDER BY max(downloads.time), crates.name, crate_versions.version
+            LIMIT $1"))?;
+        let rows = stmt.query_map(params![limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// The `limit` crates first seen most recently, with when they were first
+    /// seen, ex: `2020-01-31 12:00:00.000`.
+    pub fn newest_crates(&self, limit: usize) -> Result<Vec<(String, String)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT name, first_seen FROM {p}crates
+            WHERE first_seen IS NOT NULL
+            ORDER BY first_seen DESC, name
+            LIMIT $1"))?;
+        let rows = stmt.query_map(params![limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Crates requested within the window that failed every time they were,
+    /// ex: misspelled or deleted crates.
+    pub fn always_failing_crates(&self, window: &str) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+            GROUP BY crates.id
+            HAVING min(downloads.failed) = 1
+            ORDER BY crates.name"))?;
+        let rows = stmt.query_map(params![window], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Cached crate versions nobody has downloaded, as `(name, version)`.
+    /// Meaningless in aggregate-only mode, which keeps no download rows.
+    pub fn unrequested_prewarms(&self) -> Result<Vec<(String, String)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE NOT EXISTS (
+                SELECT 1 FROM {p}downloads AS downloads
+                WHERE downloads.version_id = crate_versions.id
+            )
+            ORDER BY crates.name, crate_versions.version"))?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Crates downloaded exactly once over all recorded history.
+    pub fn one_off_crates(&self) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            GROUP BY crates.id
+            HAVING sum(downloads.count) = 1
+            ORDER BY crates.name"))?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Every `(crate, version)` pair known to the database, ordered by crate
+    /// name then version.
+    pub fn all_versions(&self) -> Result<Vec<(String, String)>, StatsError> {
+        let mut versions = Vec::new();
+        self.for_each_version(|name, version| versions.push((name, version)))?;
+        Ok(versions)
+    }
+
+    /// Calls `f` with each `(crate, version)` pair as it is read, in the same
+    /// order as `all_versions`, without holding the whole catalog in memory.
+    pub fn for_each_version<F>(&self, mut f: F) -> Result<(), StatsError>
+        where F: FnMut(String, String)
+    {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            ORDER BY crates.name, crate_versions.version"))?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            f(row.get(0)?, row.get(1)?);
+        }
+        Ok(())
+    }
+
+    /// Crates whose downloads in the window switched between hit and miss at
+    /// least `min_flips` times, which hints at evictions or a flaky cache.
+    /// Bypasses and outage misses are ignored. The collector only folds a
+    /// crate's consecutive requests into one row, so every flip is kept.
+    pub fn flapping_crates(&self, window: &str, min_flips: i64) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT name FROM (
+                SELECT crates.name AS name,
+                       downloads.hit != lag(downloads.hit) OVER (
+                           PARTITION BY crates.id
+                           ORDER BY downloads.time, downloads.rowid
+                       ) AS flipped
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+                WHERE downloads.time > datetime('now', '-' || $1)
+                    AND downloads.bypass = 0 AND downloads.upstream_down = 0
+            )
+            GROUP BY name
+            HAVING COALESCE(sum(flipped), 0) >= $2
+            ORDER BY name"))?;
+        let rows = stmt.query_map(params![window, min_flips], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Version numbers shared by at least `min_crates` crates with the number
+    /// of crates sharing each, ex: a workspace releasing in lockstep.
+    pub fn shared_version_families(&self,
+                                   min_crates: i64)
+                                   -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT version, count(DISTINCT crate_id) AS crates
+            FROM {p}crate_versions
+            GROUP BY version
+            HAVING crates >= $1
+            ORDER BY crates DESC, version"))?;
+        let rows = stmt.query_map(params![min_crates], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Downloads, hits and bytes saved of each version of a crate in the
+    /// window, counted the same way as `stats_in`.
+    pub fn version_breakdown(&self,
+                             name: &str,
+                             window: &str)
+                             -> Result<Vec<VersionStats>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crate_versions.version,
+                   sum(downloads.count),
+                   sum(CASE WHEN downloads.hit = 1 AND downloads.bypass = 0
+                       THEN downloads.count ELSE 0 END),
+                   sum(CASE WHEN downloads.hit = 1 THEN downloads.size ELSE 0 END)
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE AND downloads.time > datetime('now', '-' || $2)
+            GROUP BY crate_versions.id
+            ORDER BY crate_versions.version"))?;
+        let rows = stmt.query_map(params![name, window], |row| {
+            Ok(VersionStats {
+                version: row.get(0)?,
+                downloads: row.get(1)?,
+                hits: row.get(2)?,
+                bytes_saved: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Distinct versions of a crate requested in the window divided by its
+    /// downloads; near 1 when almost every download is of a new version.
+    pub fn churn_ratio(&self, name: &str, window: &str) -> Result<f64, StatsError> {
+        let (versions, downloads): (i64, i64) = self.conn
+            .query_row(&self.sql("SELECT count(DISTINCT downloads.version_id),
+                   COALESCE(sum(downloads.count), 0)
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE AND downloads.time > datetime('now', '-' || $2)"),
+                       params![name, window],
+                       |row| Ok((row.get(0)?, row.get(1)?)))?;
+        if downloads == 0 {
+            return Ok(0.0);
+        }
+        Ok(versions as f64 / downloads as f64)
+    }
+
+    /// Distinct crate versions requested in the window divided by downloads;
+    /// low when traffic keeps repeating the same versions.
+    pub fn diversity_ratio(&self, window: &str) -> Result<f64, StatsError> {
+        let (versions, downloads): (i64, i64) = self.conn
+            .query_row(&self.sql("SELECT count(DISTINCT version_id), COALESCE(sum(count), 0)
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1)"),
+                       params![window],
+                       |row| Ok((row.get(0)?, row.get(1)?)))?;
+        if downloads == 0 {
+            return Ok(0.0);
+        }
+        Ok(versions as f64 / downloads as f64)
+    }
+
+    /// Crates with at least `min_downloads` requests in the window, lowest hit
+    /// ratio first. Cache-bypass requests and outage misses are left out of the ratio.
+    pub fn worst_hit_ratio_crates(&self,
+                                  window: &str,
+                                  min_downloads: i64,
+                                  limit: usize)
+                                  -> Result<Vec<(String, f64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name,
+                   CAST(sum(CASE WHEN downloads.hit = 1 THEN downloads.count ELSE 0 END) AS REAL)
+                   / sum(downloads.count) AS ratio
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+                AND downloads.bypass = 0 AND downloads.upstream_down = 0
+            GROUP BY crates.id
+            HAVING sum(downloads.count) >= $2
+            ORDER BY ratio, sum(downloads.count) DESC, crates.name
+            LIMIT $3"))?;
+        let rows = stmt.query_map(params![window, min_downloads, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Downloads per crate within the window, most downloaded first.
+    fn crate_downloads(&self, window: &str) -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, sum(downloads.count) AS total
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+            GROUP BY crates.id
+            ORDER BY total DESC, crates.name"))?;
+        let rows = stmt.query_map(params![window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Bytes saved by hits per crate within the window, biggest saver first.
+    fn crate_bytes_saved(&self, window: &str) -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, sum(downloads.size) AS saved
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1) AND downloads.hit = 1
+            GROUP BY crates.id
+            ORDER BY saved DESC, crates.name"))?;
+        let rows = stmt.query_map(params![window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn crate_id<T: Into<String>>(&self, name: T) -> Option<i32> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT id FROM {p}crates WHERE name = $1 COLLATE NOCASE"))
+            .unwrap();
+        let rows = stmt.query_map(&[&name.into()], |row| row.get(0)).unwrap();
+        for record in rows {
+            if let Ok(id) = record {
+                return Some(id);
+            }
+        }
+        return None;
+    }
+
+    fn version_id<T: Into<String>>(&self, crate_id: i32, version: T) -> Option<i32> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT id
+            FROM {p}crate_versions
+            WHERE crate_id = $1 \
+                      AND version = $2"))
+            .unwrap();
+        let rows = stmt.query_map(params![crate_id, version.into()], |row| row.get(0)).unwrap();
+        for record in rows {
+            if let Ok(id) = record {
+                return Some(id);
+            }
+        }
+        return None;
+    }
+
+    /// Inserts the crate and version rows if needed and returns the version id.
+    fn ensure_version(&self, crate_name: &str, crate_version: &str) -> Result<i32, StatsError> {
+        let crate_name = crate_name.to_lowercase();
+        self.conn
+            .execute(&self.sql("INSERT OR IGNORE INTO {p}crates (name) VALUES ($1)"),
+                     params![crate_name])?;
+        let crate_id = self.crate_id(crate_name.as_str())
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        self.conn
+            .execute(&self.sql("INSERT OR IGNORE INTO {p}crate_versions (crate_id, version) \
+                                VALUES ($1, $2)"),
+                     params![crate_id, crate_version])?;
+        self.version_id(crate_id, crate_version)
+            .ok_or_else(|| rusqlite::Error::QueryReturnedNoRows.into())
+    }
+
+    pub fn add_request<T: Into<String>, S: Into<String>>(&self,
+                                                         crate_name: T,
+                                                         crate_version: S,
+                                                         hit: bool,
+                                                         size: i64)
+                                                         -> Result<(), StatsError> {
+        let req = CargoRequest {
+            name: crate_name.into(),
+            version: crate_version.into(),
+            hit,
+            size,
+            ..Default::default()
+        };
+        self.add_requests(&req, 1)
+    }
+
+    /// Records `count` identical requests as a single row, `req.size` being
+    /// the bytes transferred by all of them together.
+    pub fn add_requests(&self, req: &CargoRequest, count: i64) -> Result<(), StatsError> {
+        self.insert_requests(req, count, &self.clock.now())
+    }
+
+    /// Like `add_requests`, recording them at `time`, anything SQLite's date
+    /// functions accept.
+    fn insert_requests(&self,
+                       req: &CargoRequest,
+                       count: i64,
+                       time: &str)
+                       -> Result<(), StatsError> {
+        if req.name.is_empty() || req.version.is_empty() {
+            return Err(StatsError::Validation("crate name and version are required".into()));
+        }
+        let version_id = self.ensure_version(&req.name, &req.version)?;
+        // A negative size would corrupt every bandwidth sum it takes part in.
+        let size = if req.size < 0 {
+            warn!("Recording negative size {} for {}:{} as 0", req.size, req.name, req.version);
+            0
+        } else {
+            req.size
+        };
+
+        trace!("Version ID: {}", version_id);
+        if self.aggregate_only {
+            // Counted the same way as `stats_in` counts download rows.
+            let crate_id = self.crate_id(req.name.as_str())
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            let hits = if req.hit && !req.bypass { count } else { 0 };
+            let misses = if !req.hit && !req.bypass && !req.upstream_down { count } else { 0 };
+            self.add_counts(crate_id, count, hits, misses, if req.hit { size } else { 0 })?;
+        } else {
+            self.insert_download(version_id, req, count, size, time)?;
+        }
+        self.conn
+            .execute(&self.sql("UPDATE {p}crates SET first_seen = strftime('%Y-%m-%d %H:%M:%f', $1)
+            WHERE id = (SELECT crate_id FROM {p}crate_versions WHERE id = $2)
+                AND (first_seen IS NULL OR first_seen > strftime('%Y-%m-%d %H:%M:%f', $1))"),
+                     params![time, version_id])?;
+        if let Some(ref parent) = req.requested_by {
+            self.conn
+                .execute(&self.sql("INSERT OR IGNORE INTO {p}dependency_edges (parent, child) \
+                                    VALUES ($1, $2)"),
+                         params![parent.to_lowercase(), req.name.to_lowercase()])?;
+        }
+        Ok(())
+    }
+
+    fn insert_download(&self,
+                       version_id: i32,
+                       req: &CargoRequest,
+                       count: i64,
+                       size: i64,
+                       time: &str)
+                       -> Result<(), StatsError> {
+        self.conn
+            .execute(&self.sql("INSERT INTO {p}downloads \
+                      (version_id, hit, count, size, client, is_range, bypass, fetch_ms, \
+                       upstream_down, failed, target, time) \
+                      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, \
+                              strftime('%Y-%m-%d %H:%M:%f', $12))"),
+                     params![version_id,
+                             req.hit,
+                             count,
+                             size,
+                             self.stored_client(&req.client),
+                             req.is_range,
+                             req.bypass,
+                             req.fetch_ms,
+                             req.upstream_down,
+                             req.failed,
+                             req.target,
+                             time])?;
+        Ok(())
+    }
+
+    /// Records one request per JSON line, ex:
+    /// `{"name": "serde", "version": "1.0.104", "hit": true, "size": 73481}`,
+    /// with an optional `time` for when it happened. Malformed lines are
+    /// skipped with a warning; returns the number of requests recorded.
+    pub fn ingest_jsonl<R: BufRead>(&self, reader: R) -> Result<usize, StatsError> {
+        self.transaction(|| {
+            let mut ingested = 0;
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match self.ingest_line(&line) {
+                    Ok(()) => ingested += 1,
+                    Err(e) => warn!("Skipping stats line {}: {}", number + 1, e),
+                }
+            }
+            Ok(ingested)
+        })
+    }
+
+    fn ingest_line(&self, line: &str) -> Result<(), StatsError> {
+        let line: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| StatsError::Validation(e.to_string()))?;
+        let text = |key: &str| match line.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(StatsError::Validation(format!("{} must be a string", key))),
+        };
+        let flag = |key: &str| match line.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(false),
+            Some(serde_json::Value::Bool(b)) => Ok(*b),
+            Some(_) => Err(StatsError::Validation(format!("{} must be a boolean", key))),
+        };
+        let size = match line.get("size") {
+            None | Some(serde_json::Value::Null) => 0,
+            Some(size) => {
+                size.as_i64()
+                    .ok_or_else(|| StatsError::Validation("size must be an integer".into()))?
+            }
+        };
+        let fetch_ms = match line.get("fetch_ms") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(ms) => {
+                Some(ms.as_i64()
+                    .ok_or_else(|| StatsError::Validation("fetch_ms must be an integer".into()))?)
+            }
+        };
+        let req = CargoRequest {
+            name: text("name")?.unwrap_or_default(),
+            version: text("version")?.unwrap_or_default(),
+            hit: flag("hit")?,
+            size,
+            client: text("client")?,
+            is_range: flag("is_range")?,
+            requested_by: text("requested_by")?,
+            bypass: flag("bypass")?,
+            fetch_ms,
+            upstream_down: flag("upstream_down")?,
+            failed: flag("failed")?,
+            target: text("target")?,
+            received_at: None,
+        };
+        let time = match text("time")? {
+            Some(time) => {
+                self.conn
+                    .query_row("SELECT strftime('%Y-%m-%d %H:%M:%f', $1)",
+                               params![time],
+                               |row| row.get::<_, Option<String>>(0))?
+                    .ok_or_else(|| StatsError::Validation(format!("bad time {:?}", time)))?
+            }
+            None => self.clock.now(),
+        };
+        self.insert_requests(&req, 1, &time)
+    }
+
+    /// Runs `f` inside a transaction, rolling back if it fails.
+    fn transaction<T, F>(&self, f: F) -> Result<T, StatsError>
+        where F: FnOnce() -> Result<T, StatsError>
+    {
+        self.conn.execute_batch("BEGIN")?;
+        let result = f().and_then(|value| {
+            self.conn.execute_batch("COMMIT")?;
+            Ok(value)
+        });
+        match result {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes every trace of a crate: its downloads, versions, dependency
+    /// edges and the crate itself. Returns the number of rows deleted.
+    pub fn delete_crate(&self, name: &str) -> Result<usize, StatsError> {
+        let crate_id = match self.crate_id(name) {
+            Some(id) => id,
+            None => return Ok(0),
+        };
+        self.transaction(|| {
+            let mut deleted = self.conn
+                .execute(&self.sql("DELETE FROM {p}downloads WHERE version_id IN
+                (SELECT id FROM {p}crate_versions WHERE crate_id = $1)"),
+                         params![crate_id])?;
+            deleted += self.conn
+                .execute(&self.sql("DELETE FROM {p}crate_versions WHERE crate_id = $1"),
+                         params![crate_id])?;
+            deleted += self.conn
+                .execute(&self.sql("DELETE FROM {p}dependency_edges
+                WHERE parent = $1 COLLATE NOCASE OR child = $1 COLLATE NOCASE"),
+                         params![name])?;
+            deleted += self.conn
+                .execute(&self.sql("DELETE FROM {p}counters WHERE crate_id = $1"),
+                         params![crate_id])?;
+            deleted += self.conn
+                .execute(&self.sql("DELETE FROM {p}crates WHERE id = $1"), params![crate_id])?;
+            Ok(deleted)
+        })
+    }
+
+    /// Moves the stats of crate `old` under the name `new`, creating `new` if
+    /// needed and merging into its versions when both crates have some.
+    pub fn rename_crate(&self, old: &str, new: &str) -> Result<(), StatsError> {
+        let old_id = self.crate_id(old)
+            .ok_or_else(|| StatsError::Validation(format!("no stats for crate {:?}", old)))?;
+        let new_name = new.to_lowercase();
+        self.transaction(|| {
+            if self.crate_id(new) == Some(old_id) {
+                self.conn
+                    .execute(&self.sql("UPDATE {p}crates SET name = $1 WHERE id = $2"),
+                             params![new_name, old_id])?;
+                return Ok(());
+            }
+            self.conn
+                .execute(&self.sql("INSERT OR IGNORE INTO {p}crates (name) VALUES ($1)"),
+                         params![new_name])?;
+            let new_id = self.crate_id(new).ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            self.merge_crate(old_id, new_id)?;
+            self.rename_edges(old, &new_name)
+        })
+    }
+
+    /// Moves everything recorded for crate `old_id` onto `new_id` and removes
+    /// the `old_id` row. Dependency edges are keyed by name and left alone.
+    fn merge_crate(&self, old_id: i32, new_id: i32) -> Result<(), StatsError> {
+        // Versions the new crate already has keep their row; their
+        // downloads move over before the old duplicates go.
+        self.conn
+            .execute(&self.sql("UPDATE OR IGNORE {p}crate_versions SET crate_id = $1
+            WHERE crate_id = $2"),
+                     params![new_id, old_id])?;
+        self.conn
+            .execute(&self.sql("UPDATE {p}downloads SET version_id = (
+                SELECT new_versions.id
+                FROM {p}crate_versions AS old_versions
+                INNER JOIN {p}crate_versions AS new_versions
+                    ON new_versions.version = old_versions.version
+                WHERE old_versions.id = {p}downloads.version_id
+                    AND new_versions.crate_id = $1
+            )
+            WHERE version_id IN (SELECT id FROM {p}crate_versions WHERE crate_id = $2)"),
+                     params![new_id, old_id])?;
+        self.conn
+            .execute(&self.sql("DELETE FROM {p}crate_versions WHERE crate_id = $1"),
+                     params![old_id])?;
+        let counts = self.conn
+            .query_row(&self.sql("SELECT downloads, hits, misses, bytes FROM {p}counters
+            WHERE crate_id = $1"),
+                       params![old_id],
+                       |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .optional()?;
+        if let Some((downloads, hits, misses, bytes)) = counts {
+            self.add_counts(new_id, downloads, hits, misses, bytes)?;
+            self.conn
+                .execute(&self.sql("DELETE FROM {p}counters WHERE crate_id = $1"),
+                         params![old_id])?;
+        }
+        self.conn
+            .execute(&self.sql("UPDATE {p}crates SET first_seen = (
+                SELECT min(first_seen) FROM {p}crates WHERE id IN ($1, $2)
+            )
+            WHERE id = $1"),
+                     params![new_id, old_id])?;
+        self.conn
+            .execute(&self.sql("DELETE FROM {p}crates WHERE id = $1"), params![old_id])?;
+        Ok(())
+    }
+
+    /// Points dependency edges naming `old` at `new` instead.
+    fn rename_edges(&self, old: &str, new: &str) -> Result<(), StatsError> {
+        for column in &["parent", "child"] {
+            self.conn
+                .execute(&self.sql(&format!("UPDATE OR IGNORE {{p}}dependency_edges SET {c} = $1
+                WHERE {c} = $2 COLLATE NOCASE",
+                                            c = column)),
+                         params![new, old])?;
+            self.conn
+                .execute(&self.sql(&format!("DELETE FROM {{p}}dependency_edges
+                WHERE {c} = $1 COLLATE NOCASE",
+                                            c = column)),
+                         params![old])?;
+        }
+        Ok(())
+    }
+
+    /// Merges crates whose names differ only in case onto the oldest row
+    /// among them and renames every crate to the lowercase name new crates
+    /// are stored under, returning how many rows were merged. Names differing
+    /// in `-` versus `_` are kept apart, as lookups tell them apart too.
+    pub fn dedupe_crates(&self) -> Result<usize, StatsError> {
+        let crates = {
+            let mut stmt = self.conn
+                .prepare(&self.sql("SELECT id, name FROM {p}crates ORDER BY id"))?;
+            let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<Vec<(i32, String)>, _>>()?
+        };
+        let merged = self.transaction(|| {
+            let mut kept: HashMap<String, i32> = HashMap::new();
+            let mut merged = 0;
+            for (id, name) in crates {
+                let canonical = name.to_lowercase();
+                let new_id = match kept.get(&canonical) {
+                    Some(&survivor) => survivor,
+                    None => {
+                        kept.insert(canonical, id);
+                        continue;
+                    }
+                };
+                self.merge_crate(id, new_id)?;
+                merged += 1;
+            }
+            // Renamed once all duplicates are gone, one of them may already
+            // hold the lowercase name.
+            for (canonical, id) in kept {
+                self.conn
+                    .execute(&self.sql("UPDATE {p}crates SET name = $1 WHERE id = $2"),
+                             params![canonical, id])?;
+            }
+            Ok(merged)
+        })?;
+        if merged > 0 {
+            self.index_crate_names()?;
+        }
+        Ok(merged)
+    }
+
+    /// Adds the crates, versions, downloads, counters and dependency edges of
+    /// another stats database file, matching crates by name and versions by
+    /// number; a crate keeps the earlier of the two first-seen times. Merging
+    /// the same file twice counts its downloads twice.
+    pub fn merge_from(&self, path: &str) -> Result<(), StatsError> {
+        // ATTACH would create an empty database in place of a missing file.
+        if !Path::new(path).is_file() {
+            return Err(StatsError::Io(io::Error::new(io::ErrorKind::NotFound,
+                                                     format!("no stats database at {}", path))));
+        }
+        self.conn.execute("ATTACH DATABASE $1 AS merge_source", params![path])?;
+        let result = self.transaction(|| self.merge_attached());
+        self.conn.execute_batch("DETACH DATABASE merge_source")?;
+        result
+    }
+
+    fn merge_attached(&self) -> Result<(), StatsError> {
+        self.conn
+            .execute(&self.sql("INSERT OR IGNORE INTO {p}crates (name)
+            SELECT lower(name) FROM merge_source.{p}crates"),
+                     params![])?;
+        self.conn
+            .execute(&self.sql("INSERT OR IGNORE INTO {p}crate_versions (crate_id, version)
+            SELECT crates.id, source_versions.version
+            FROM merge_source.{p}crate_versions AS source_versions
+            INNER JOIN merge_source.{p}crates AS source_crates
+                ON source_crates.id = source_versions.crate_id
+            INNER JOIN {p}crates AS crates ON crates.name = source_crates.name COLLATE NOCASE"),
+                     params![])?;
+
+        // Older databases may lack some of the newer download columns.
+        let ours = table_columns(&self.conn, "main", &self.sql("{p}downloads"))?;
+        let columns = table_columns(&self.conn, "merge_source", &self.sql("{p}downloads"))?
+            .into_iter()
+            .filter(|c| c != "version_id" && ours.contains(c))
+            .map(|c| quote_identifier(&c))
+            .collect::<Vec<_>>();
+        let source_columns = columns.iter()
+            .map(|c| format!("source_downloads.{}", c))
+            .collect::<Vec<_>>();
+        self.conn
+            .execute(&self.sql(&format!("INSERT INTO {{p}}downloads (version_id, {})
+            SELECT crate_versions.id, {}
+            FROM merge_source.{{p}}downloads AS source_downloads
+            INNER JOIN merge_source.{{p}}crate_versions AS source_versions
+                ON source_versions.id = source_downloads.version_id
+            INNER JOIN merge_source.{{p}}crates AS source_crates
+                ON source_crates.id = source_versions.crate_id
+            INNER JOIN {{p}}crates AS crates ON crates.name = source_crates.name COLLATE NOCASE
+            INNER JOIN {{p}}crate_versions AS crate_versions ON crate_versions.crate_id = crates.id
+                AND crate_versions.version = source_versions.version",
+                                        columns.join(", "),
+                                        source_columns.join(", "))),
+                     params![])?;
+
+        if table_columns(&self.conn, "merge_source", &self.sql("{p}crates"))?
+            .iter()
+            .any(|c| c == "first_seen") {
+            self.conn
+                .execute(&self.sql("UPDATE {p}crates SET first_seen = (
+                    SELECT min(source_crates.first_seen)
+                    FROM merge_source.{p}crates AS source_crates
+                    WHERE source_crates.name = {p}crates.name COLLATE NOCASE
+                )
+                WHERE EXISTS (
+                    SELECT 1 FROM merge_source.{p}crates AS source_crates
+                    WHERE source_crates.name = {p}crates.name COLLATE NOCASE
+                        AND source_crates.first_seen IS NOT NULL
+                        AND ({p}crates.first_seen IS NULL
+                            OR source_crates.first_seen < {p}crates.first_seen)
+                )"),
+                         params![])?;
+        }
+        self.backfill_first_seen()?;
+
+        // Databases from before `counters` have no totals to add.
+        if !table_columns(&self.conn, "merge_source", &self.sql("{p}counters"))?.is_empty() {
+            let mut stmt = self.conn
+                .prepare(&self.sql("SELECT crates.id, sum(source_counters.downloads),
+                    sum(source_counters.hits), sum(source_counters.misses),
+                    sum(source_counters.bytes)
+                FROM merge_source.{p}counters AS source_counters
+                INNER JOIN merge_source.{p}crates AS source_crates
+                    ON source_crates.id = source_counters.crate_id
+                INNER JOIN {p}crates AS crates ON crates.name = source_crates.name COLLATE NOCASE
+                GROUP BY crates.id"))?;
+            let totals = stmt.query_map(params![], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                })?
+                .collect::<Result<Vec<(i32, i64, i64, i64, i64)>, _>>()?;
+            for (crate_id, downloads, hits, misses, bytes) in totals {
+                self.add_counts(crate_id, downloads, hits, misses, bytes)?;
+            }
+        }
+
+        if !table_columns(&self.conn, "merge_source", &self.sql("{p}dependency_edges"))?
+            .is_empty() {
+            self.conn
+                .execute(&self.sql("INSERT OR IGNORE INTO {p}dependency_edges (parent, child)
+                    SELECT lower(parent), lower(child)
+                    FROM merge_source.{p}dependency_edges"),
+                         params![])?;
+        }
+        Ok(())
+    }
+
+    /// Records a cached crate version without a download, ex: one pre-fetched
+    /// before anyone asked for it.
+    pub fn record_cached(&self, crate_name: &str, crate_version: &str) -> Result<(), StatsError> {
+        self.ensure_version(crate_name, crate_version)?;
+        Ok(())
+    }
+
+    /// Stores the checksum of a cached crate file unless one is already recorded.
+    pub fn record_checksum(&self,
+                           crate_name: &str,
+                           crate_version: &str,
+                           checksum: &str)
+                           -> Result<(), StatsError> {
+        let version_id = self.ensure_version(crate_name, crate_version)?;
+        self.conn
+            .execute(&self.sql("UPDATE {p}crate_versions SET checksum = $1 \
+                                WHERE id = $2 AND checksum IS NULL"),
+                     params![checksum, version_id])?;
+        Ok(())
+    }
+
+    /// Records whether the version is yanked upstream.
+    pub fn mark_yanked(&self,
+                       crate_name: &str,
+                       crate_version: &str,
+                       yanked: bool)
+                       -> Result<(), StatsError> {
+        let version_id = self.ensure_version(crate_name, crate_version)?;
+        self.conn
+            .execute(&self.sql("UPDATE {p}crate_versions SET yanked = $1 WHERE id = $2"),
+                     params![yanked, version_id])?;
+        Ok(())
+    }
+
+    /// Cached versions of a crate, oldest first by semver. Versions that
+    /// aren't valid semver sort lexically after all the others.
+    pub fn sorted_versions(&self, name: &str) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crate_versions.version
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE"))?;
+        let mut versions = stmt.query_map(params![name], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        versions.sort_by(|a, b| cmp_versions(a, b));
+        Ok(versions)
+    }
+
+    /// Cached versions that have since been yanked upstream, as `(name, version)`.
+    pub fn cached_yanked_versions(&self) -> Result<Vec<(String, String)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crate_versions.yanked = 1
+            ORDER BY crates.name, crate_versions.version"))?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Whether a checksum was recorded for the version and differs from `observed`.
+    pub fn checksum_mismatch(&self,
+                             crate_name: &str,
+                             crate_version: &str,
+                             observed: &str)
+                             -> Result<bool, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crate_versions.checksum
+            FROM {p}crate_versions AS crate_versions
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE crates.name = $1 COLLATE NOCASE AND crate_versions.version = $2"))?;
+        let mut rows = stmt.query(params![crate_name, crate_version])?;
+        match rows.next()? {
+            Some(row) => {
+                let stored: Option<String> = row.get(0)?;
+                match stored {
+                    Some(stored) => Ok(stored != observed),
+                    None => Ok(false),
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Schema version the database has been migrated to, from
+    /// `PRAGMA user_version`; 0 for databases no release has migrated yet.
+    pub fn schema_version(&self) -> Result<i32, StatsError> {
+        Ok(self.conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    /// Everything needed to reproduce an issue with the stats database.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let path = self.conn
+            .query_row("SELECT file FROM pragma_database_list WHERE name = 'main'",
+                       params![],
+                       |row| row.get::<_, String>(0))
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| ":memory:".to_string());
+        let user_version = self.conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap_or(0);
+        let table_rows = self.table_rows().unwrap_or_default();
+        Diagnostics {
+            sqlite_version: rusqlite::version(),
+            path,
+            user_version,
+            table_rows,
+        }
+    }
+
+    /// Row counts of the stats tables and the size of the database.
+    pub fn db_footprint(&self) -> Result<DbFootprint, StatsError> {
+        let page_count: i64 = self.conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        let page_size: i64 = self.conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        Ok(DbFootprint {
+            table_rows: self.table_rows()?,
+            size_bytes: page_count * page_size,
+        })
+    }
+
+    /// Refreshes the query planner's statistics, worth running after large
+    /// imports or deletions.
+    pub fn optimize(&self) -> Result<(), StatsError> {
+        self.conn.execute_batch("ANALYZE; PRAGMA optimize;")?;
+        Ok(())
+    }
+
+    fn table_rows(&self) -> Result<Vec<(String, i64)>, rusqlite::Error> {
+        self.tables()?
+            .into_iter()
+            .map(|table| {
+                let count = self.conn
+                    .query_row(&format!("SELECT count(*) FROM {}", quote_identifier(&table)),
+                               params![],
+                               |row| row.get(0))?;
+                Ok((table, count))
+            })
+            .collect()
+    }
+
+    /// This database's tables, by their prefixed names; tables of other
+    /// prefixes sharing the file are never included.
+    fn tables(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self.conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")?;
+        let names = stmt.query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(names.into_iter().filter(|name| self.owns_table(name)).collect())
+    }
+
+    fn owns_table(&self, name: &str) -> bool {
+        name.starts_with(&self.table_prefix) && TABLES.contains(&&name[self.table_prefix.len()..])
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT value FROM {p}collector_meta WHERE key = $1"))?;
+        let mut rows = stmt.query(params![key])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    fn set_meta(&self, key: &str, value: &str) -> Result<(), StatsError> {
+        self.conn
+            .execute(&self.sql("INSERT OR REPLACE INTO {p}collector_meta (key, value) \
+                                VALUES ($1, $2)"),
+                     params![key, value])?;
+        Ok(())
+    }
+
+    /// Requests the collector had to drop, as last persisted by it.
+    pub fn dropped_events(&self) -> Result<u64, StatsError> {
+        match self.meta(DROPPED_EVENTS)? {
+            Some(value) => {
+                value.parse().map_err(|_| {
+                    StatsError::Validation(format!("bad dropped event count {}", value))
+                })
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Stores the current time under `key`.
+    fn set_meta_now(&self, key: &str) -> Result<(), StatsError> {
+        self.conn
+            .execute(&self.sql("INSERT OR REPLACE INTO {p}collector_meta (key, value) \
+                      VALUES ($1, strftime('%Y-%m-%d %H:%M:%f', 'now'))"),
+                     params![key])?;
+        Ok(())
+    }
+
+    /// When the current collector started, ex: `2020-01-31 12:00:00.000`.
+    pub fn started_at(&self) -> Result<Option<String>, StatsError> {
+        self.meta(STARTED_AT)
+    }
+
+    /// When the collector last wrote its buffered requests out. A time older
+    /// than the flush interval means the collector is stuck.
+    pub fn last_flush_at(&self) -> Result<Option<String>, StatsError> {
+        self.meta(LAST_FLUSH_AT)
+    }
+
+    /// Whole seconds since the current collector started.
+    pub fn uptime_secs(&self) -> Result<Option<i64>, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT
+                   CAST(round((julianday('now') - julianday(value)) * 86400) AS INTEGER)
+            FROM {p}collector_meta
+            WHERE key = $1"),
+                       params![STARTED_AT],
+                       |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Crates recorded as having pulled in `child`.
+    pub fn who_pulls(&self, child: &str) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT parent FROM {p}dependency_edges
+            WHERE child = $1 COLLATE NOCASE
+            ORDER BY parent"))?;
+        let rows = stmt.query_map(params![child], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Bytes served to each client within the window, largest first, with
+    /// requests of unknown origin grouped as `unknown`.
+    pub fn bandwidth_by_client(&self,
+                               window: &str,
+                               limit: usize)
+                               -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT COALESCE(client, 'unknown') AS origin, sum(size) AS bytes
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1)
+            GROUP BY origin
+            ORDER BY bytes DESC, origin
+            LIMIT $2"))?;
+        let rows = stmt.query_map(params![window, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Requests within the window per build target, busiest first, with
+    /// requests that didn't name a target grouped as `unknown`.
+    pub fn target_breakdown(&self, window: &str) -> Result<Vec<(String, i64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT COALESCE(target, 'unknown') AS build_target,
+                sum(count) AS requests
+            FROM {p}downloads
+            WHERE time > datetime('now', '-' || $1)
+            GROUP BY build_target
+            ORDER BY requests DESC, build_target"))?;
+        let rows = stmt.query_map(params![window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Distinct (client, crate) combinations seen in the window.
+    pub fn distinct_client_crate_pairs(&self, window: &str) -> Result<i64, StatsError> {
+        Ok(self.conn
+            .query_row(&self.sql("SELECT count(*) FROM (
+                SELECT DISTINCT downloads.client, crate_versions.crate_id
+                FROM {p}downloads AS downloads
+                INNER JOIN {p}crate_versions AS crate_versions
+                    ON crate_versions.id = downloads.version_id
+                WHERE downloads.time > datetime('now', '-' || $1)
+                    AND downloads.client IS NOT NULL
+            )"),
+                       params![window],
+                       |row| row.get(0))?)
+    }
+
+    /// Crate versions with a zero-size download recorded in the window, which
+    /// usually means a broken fetch. Failed requests and outage misses are not
+    /// included.
+    pub fn zero_size_requests(&self, window: &str) -> Result<Vec<(String, String)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT DISTINCT crates.name, crate_versions.version
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+                AND downloads.size = 0 AND downloads.failed = 0 AND downloads.upstream_down = 0
+            ORDER BY crates.name, crate_versions.version"))?;
+        let rows = stmt.query_map(params![window], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Misses in the window counted by upstream fetch time, one entry per
+    /// ascending upper bound in `buckets_ms` (inclusive) plus a final
+    /// `i64::MAX` entry for slower fetches. Misses without a `fetch_ms` are skipped.
+    pub fn miss_latency_histogram(&self,
+                                  window: &str,
+                                  buckets_ms: &[i64])
+                                  -> Result<Vec<(i64, i64)>, StatsError> {
+        if buckets_ms.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(StatsError::Validation("latency buckets must be ascending".into()));
+        }
+        let mut histogram = buckets_ms.iter()
+            .cloned()
+            .chain(Some(i64::MAX))
+            .map(|bound| (bound, 0))
+            .collect::<Vec<_>>();
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT fetch_ms, sum(count) FROM {p}downloads
+            WHERE hit = 0 AND fetch_ms IS NOT NULL AND time > datetime('now', '-' || $1)
+            GROUP BY fetch_ms"))?;
+        let rows = stmt.query_map(params![window], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (fetch_ms, count) = row?;
+            let bucket = histogram.iter().position(|&(bound, _)| fetch_ms <= bound).unwrap();
+            histogram[bucket].1 += count;
+        }
+        Ok(histogram)
+    }
+
+    /// Versions whose misses in the window took longest to fetch from
+    /// upstream on average, as `(name, version, avg_fetch_ms)`.
+    pub fn slowest_fetches(&self,
+                           window: &str,
+                           limit: usize)
+                           -> Result<Vec<(String, String, f64)>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version,
+                CAST(sum(downloads.fetch_ms * downloads.count) AS REAL)
+                    / sum(downloads.count) AS avg_fetch_ms
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+                AND downloads.hit = 0 AND downloads.fetch_ms IS NOT NULL
+            GROUP BY crate_versions.id
+            HAVING sum(downloads.count) > 0
+            ORDER BY avg_fetch_ms DESC, crates.name, crate_versions.version
+            LIMIT $2"))?;
+        let rows = stmt.query_map(params![window, limit as i64],
+                                  |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Crates that only a single client requested within the window.
+    pub fn single_client_crates(&self, window: &str) -> Result<Vec<String>, StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1) AND downloads.client IS NOT NULL
+            GROUP BY crates.id
+            HAVING count(DISTINCT downloads.client) = 1
+            ORDER BY crates.name"))?;
+        let rows = stmt.query_map(params![window], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Writes every download row in the window as one JSON object per line,
+    /// without buffering the table in memory.
+    pub fn stream_ndjson<W: Write>(&self, out: &mut W, window: &str) -> Result<(), StatsError> {
+        let mut stmt = self.conn
+            .prepare(&self.sql("SELECT crates.name, crate_versions.version, downloads.time,
+                downloads.hit, downloads.count, downloads.size
+            FROM {p}downloads AS downloads
+            INNER JOIN {p}crate_versions AS crate_versions
+                ON crate_versions.id = downloads.version_id
+            INNER JOIN {p}crates AS crates ON crates.id = crate_versions.crate_id
+            WHERE downloads.time > datetime('now', '-' || $1)
+            ORDER BY downloads.time"))
+            ?;
+        let mut rows = stmt.query(params![window])?;
+        while let Some(row) = rows.next()? {
+            let record = json!({
+                "crate": row.get::<_, String>(0)?,
+                "version": row.get::<_, String>(1)?,
+                "time": row.get::<_, String>(2)?,
+                "hit": row.get::<_, bool>(3)?,
+                "count": row.get::<_, i64>(4)?,
+                "size": row.get::<_, i64>(5)?,
+            });
+            serde_json::to_writer(&mut *out, &record).map_err(io::Error::from)?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Exports the schema and every row as SQL statements that can be replayed
+    /// into an empty SQLite database.
+    pub fn dump_sql(&self) -> Result<String, StatsError> {
+        let mut stmt = self.conn
+            .prepare("SELECT type, name, tbl_name, sql FROM sqlite_master
+            WHERE sql IS NOT NULL
+            ORDER BY CASE type WHEN 'table' THEN 0 ELSE 1 END, name")?;
+        let schema = stmt.query_map(params![], |row| {
+                Ok((row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut dump = String::from("BEGIN TRANSACTION;\n");
+        for (kind, name, table, sql) in schema {
+            if !self.owns_table(&table) {
+                continue;
+            }
+            dump.push_str(&sql);
+            dump.push_str(";\n");
+            if kind == "table" {
+                self.dump_rows(&name, &mut dump)?;
+            }
+        }
+        dump.push_str("COMMIT;\n");
+        Ok(dump)
+    }
+
+    fn dump_rows(&self, table: &str, dump: &mut String) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT * FROM {}", quote_identifier(table)))?;
+        let columns = stmt.column_names()
+            .iter()
+            .map(|c| quote_identifier(c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            let values = (0..column_count)
+                .map(|i| sql_literal(row.get_raw(i)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            dump.push_str(&format!("INSERT INTO {} ({}) VALUES ({});\n",
+                                   quote_identifier(table),
+                                   columns,
+                                   values));
+        }
+        Ok(())
+    }
+}
+
+/// Adds a column to a table created by an older version of the schema.
+fn add_column(conn: &rusqlite::Connection,
+              table: &str,
+              column: &str,
+              definition: &str)
+              -> Result<(), rusqlite::Error> {
+    if !table_columns(conn, "main", table)?.iter().any(|c| c == column) {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}",
+                              quote_identifier(table),
+                              quote_identifier(column),
+                              definition),
+                     params![])?;
+    }
+    Ok(())
+}
+
+fn table_columns(conn: &rusqlite::Connection,
+                 schema: &str,
+                 table: &str)
+                 -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA {}.table_info({})",
+                                         quote_identifier(schema),
+                                         quote_identifier(table)))?;
+    let columns = stmt.query_map(params![], |row| row.get(1))?;
+    columns.collect()
+}
+
+/// strftime format of a report bucket.
+fn bucket_format(bucket: &str) -> Result<&'static str, StatsError> {
+    match bucket {
+        "minute" => Ok("%Y-%m-%d %H:%M:00"),
+        "hour" => Ok("%Y-%m-%d %H:00:00"),
+        "day" => Ok("%Y-%m-%d"),
+        "month" => Ok("%Y-%m"),
+        _ => Err(StatsError::Validation(format!("unknown bucket {:?}", bucket))),
+    }
+}
+
+/// Minutes ahead of UTC of an offset like `+02:00` or `-05:30`.
+fn parse_utc_offset(offset: &str) -> Option<i32> {
+    let (sign, rest) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return None,
+    };
+    // Plain digits only, `parse` would take another sign.
+    let number = |part: &str| {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse::<i32>().ok()
+    };
+    let mut parts = rest.splitn(2, ':');
+    let hours = number(parts.next()?)?;
+    let minutes = number(parts.next().unwrap_or("0"))?;
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// Orders versions by semver, with versions that aren't valid semver sorting
+/// lexically after all the others.
+fn cmp_versions(a: &str, b: &str) -> cmp::Ordering {
+    match (Version::parse(a), Version::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => cmp::Ordering::Less,
+        (Err(_), Ok(_)) => cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn sql_literal(value: ValueRef) -> String {
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(i) => i.to_string(),
+        // SQLite reads overflowing literals as infinities and has none for NaN.
+        ValueRef::Real(f) if f.is_nan() => "NULL".to_string(),
+        ValueRef::Real(f) if f.is_infinite() => {
+            if f > 0.0 { "9e999" } else { "-9e999" }.to_string()
+        }
+        ValueRef::Real(f) => format!("{:?}", f),
+        ValueRef::Text(s) => format!("'{}'", String::from_utf8_lossy(s).replace('\'', "''")),
+        ValueRef::Blob(b) => {
+            let hex = b.iter().map(|byte| format!("{:02X}", byte)).collect::<String>();
+            format!("X'{}'", hex)
+        }
+    }
+}
+
+/// Runs `Database` calls on tokio's blocking thread pool so async handlers
+/// can await them instead of blocking their executor. Calls take turns on
+/// the one connection; made outside of a tokio runtime, they fail with
+/// `StatsError::NoRuntime`.
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    db: Arc<Mutex<Database>>,
+}
+
+impl AsyncDatabase {
+    pub fn new(db: Database) -> AsyncDatabase {
+        AsyncDatabase { db: Arc::new(Mutex::new(db)) }
+    }
+
+    pub fn add_request<T: Into<String>, S: Into<String>>(&self,
+                                                         crate_name: T,
+                                                         crate_version: S,
+                                                         hit: bool,
+                                                         size: i64)
+                                                         -> Blocking<()> {
+        let crate_name = crate_name.into();
+        let crate_version = crate_version.into();
+        self.run(move |db| db.add_request(crate_name, crate_version, hit, size))
+    }
+
+    pub fn stats(&self) -> Blocking<Statistics> {
+        self.run(|db| Ok(db.stats()))
+    }
+
+    pub fn crates(&self) -> Blocking<Vec<Crate>> {
+        self.run(|db| db.crates())
+    }
+
+    fn run<T, F>(&self, f: F) -> Blocking<T>
+        where T: Send + 'static,
+              F: FnOnce(&Database) -> Result<T, StatsError> + Send + 'static
+    {
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return Blocking { handle: None },
+        };
+        let db = self.db.clone();
+        Blocking {
+            handle: Some(runtime.spawn_blocking(move || {
+                let db = db.lock().unwrap_or_else(|poisoned| {
+                    // A call panicked, maybe halfway through a transaction.
+                    let db = poisoned.into_inner();
+                    if !db.conn.is_autocommit() {
+                        let _ = db.conn.execute_batch("ROLLBACK");
+                    }
+                    db
+                });
+                f(&db)
+            })),
+        }
+    }
+}
+
+/// Result of a call made through `AsyncDatabase`, ready once a blocking
+/// thread has run it, or right away if there was no runtime to run it on.
+pub struct Blocking<T> {
+    /// `None` when the call was made outside of a tokio runtime
+    handle: Option<tokio::task::JoinHandle<Result<T, StatsError>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = Result<T, StatsError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let handle = match self.handle {
+            Some(ref mut handle) => handle,
+            None => return Poll::Ready(Err(StatsError::NoRuntime)),
+        };
+        match Pin::new(handle).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(StatsError::Task(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Summarises collector activity at `info` level at most once per interval;
+/// individual requests are only logged at `trace`.
+struct RequestLogSummary {
+    requests: u64,
+    since: Instant,
+}
+
+impl RequestLogSummary {
+    fn new() -> RequestLogSummary {
+        RequestLogSummary {
+            requests: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, req: &CargoRequest) {
+        log_request(req);
+        self.requests += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed >= LOG_SUMMARY_INTERVAL {
+            info!("Logged {} crate requests to sqlite in the last {:?}",
+                  self.requests,
+                  elapsed);
+            self.requests = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
+/// Logs a request at `trace` with its crate, version, hit and size attached as
+/// structured fields, so log tooling can filter on them.
+fn log_request(req: &CargoRequest) {
+    if !log_enabled!(Level::Trace) {
+        return;
+    }
+    let fields: &[_] = &[("crate", req.name.to_value()),
+                         ("version", req.version.to_value()),
+                         ("hit", req.hit.to_value()),
+                         ("size", req.size.to_value())];
+    log::logger().log(&Record::builder()
+        .args(format_args!("Logging a crate request to sqlite: {} {}", req.name, req.version))
+        .level(Level::Trace)
+        .target(module_path!())
+        .module_path(Some(module_path!()))
+        .file(Some(file!()))
+        .line(Some(line!()))
+        .key_values(&fields)
+        .build());
+}
+
+/// Requests received since the last flush, in arrival order. A request joins
+/// the latest row of its crate when it only differs in size and receive time,
+/// so repetitive traffic turns into a handful of rows while each crate's hits
+/// and misses keep their order.
+struct Aggregate {
+    /// Rows with their request count and total size; each row keeps the
+    /// receive time of its first request
+    pending: Vec<(CargoRequest, i64, i64)>,
+    /// Index in `pending` of the latest row of each crate, by lowercase name
+    latest: HashMap<String, usize>,
+}
+
+impl Aggregate {
+    fn new() -> Aggregate {
+        Aggregate {
+            pending: Vec::new(),
+            latest: HashMap::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.pending.len() >= MAX_PENDING_ROWS
+    }
+
+    /// Adds a request standing in for `weight` requests of the same kind,
+    /// returning false if it needed a row of its own while full. Misses only
+    /// merge when their `fetch_ms` matches, which keeps the latency histogram
+    /// exact.
+    fn add(&mut self, req: CargoRequest, weight: i64) -> bool {
+        let key = req.name.to_lowercase();
+        if let Some(&index) = self.latest.get(&key) {
+            let row = &mut self.pending[index];
+            if same_kind(&row.0, &req) {
+                row.1 += weight;
+                row.2 += req.size * weight;
+                return true;
+            }
+        }
+        if self.is_full() {
+            return false;
+        }
+        let size = req.size * weight;
+        self.latest.insert(key, self.pending.len());
+        self.pending.push((req, weight, size));
+        true
+    }
+
+    /// Writes every pending row in one transaction; nothing is discarded if it fails.
+    fn flush(&mut self, db: &Database) -> Result<(), StatsError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        db.transaction(|| {
+                for &(ref req, count, size) in &self.pending {
+                    let req = CargoRequest { size, ..req.clone() };
+                    match req.received_at {
+                        Some(received_at) => {
+                            let time = humantime::format_rfc3339_millis(received_at).to_string();
+                            db.insert_requests(&req, count, &time)?
+                        }
+                        None => db.add_requests(&req, count)?,
+                    }
+                }
+                Ok(())
+            })?;
+        debug!("Flushed {} aggregated download rows", self.pending.len());
+        self.pending.clear();
+        self.latest.clear();
+        Ok(())
+    }
+}
+
+/// Whether two requests only differ in size and receive time.
+fn same_kind(a: &CargoRequest, b: &CargoRequest) -> bool {
+    let kind = |req: &CargoRequest| {
+        CargoRequest {
+            size: 0,
+            received_at: None,
+            ..req.clone()
+        }
+    };
+    kind(a) == kind(b)
+}
+
+/// Alerts when the recent cache hit ratio falls below a threshold, e.g. after
+/// an upstream change makes every request miss.
+pub struct HitRatioWatchdog {
+    threshold: f64,
+    alert: Box<dyn Fn(f64) + Send>,
+}
+
+impl HitRatioWatchdog {
+    /// A watchdog that logs a warning when it fires.
+    pub fn new(threshold: f64) -> HitRatioWatchdog {
+        HitRatioWatchdog::with_alert(threshold, move |ratio| {
+            warn!("Cache hit ratio over the last {} is {:.2}, below {:.2}",
+                  WATCHDOG_WINDOW,
+                  ratio,
+                  threshold)
+        })
+    }
+
+    pub fn with_alert<F: Fn(f64) + Send + 'static>(threshold: f64, alert: F) -> HitRatioWatchdog {
+        HitRatioWatchdog {
+            threshold,
+            alert: Box::new(alert),
+        }
+    }
+
+    /// Compares the hit ratio over the watchdog window with the threshold,
+    /// returning the ratio if the alert fired.
+    pub fn check(&self, db: &Database) -> Option<f64> {
+        let downloads = db.downloads(WATCHDOG_WINDOW) - db.bypasses(WATCHDOG_WINDOW) as i32 -
+                        db.outage_misses(WATCHDOG_WINDOW) as i32;
+        if downloads == 0 {
+            return None;
+        }
+        let ratio = f64::from(db.hits(WATCHDOG_WINDOW)) / f64::from(downloads);
+        if ratio < self.threshold {
+            (self.alert)(ratio);
+            Some(ratio)
+        } else {
+            None
+        }
+    }
+}
+
+/// How long the collector's flush transactions take; rising figures mean the
+/// database is struggling to keep up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CommitLatency {
+    /// Exponential moving average, the latest commit weighing in at 20%
+    pub average: Duration,
+    pub max: Duration,
+    pub commits: u64,
+}
+
+impl CommitLatency {
+    fn record(&mut self, took: Duration) {
+        self.average = if self.commits == 0 {
+            took
+        } else {
+            self.average.mul_f64(1.0 - COMMIT_LATENCY_WEIGHT) + took.mul_f64(COMMIT_LATENCY_WEIGHT)
+        };
+        self.max = cmp::max(self.max, took);
+        self.commits += 1;
+    }
+}
+
+/// Pushes statistics to a Prometheus Pushgateway, for deployments that can't
+/// be scraped.
+pub struct Pushgateway {
+    url: String,
+    job: String,
+    in_flight: Arc<AtomicBool>,
+}
+
+impl Pushgateway {
+    pub fn new<T: Into<String>, S: Into<String>>(url: T, job: S) -> Pushgateway {
+        Pushgateway {
+            url: url.into(),
+            job: job.into(),
+            in_flight: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Replaces the job's metrics on the gateway with `stats`.
+    pub fn push(&self, stats: &Statistics) -> Result<(), io::Error> {
+        let url = format!("{}/metrics/job/{}",
+                          self.url.trim_end_matches('/'),
+                          encode_path_segment(&self.job));
+        let mut curl = Command::new("curl")
+            .arg("--data-binary")
+            .arg("@-") // Body from stdin
+            .arg("-f") // Fail on HTTP errors
+            .arg("-s") // Quietly!
+            .arg("--max-time")
+            .arg("10")
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        curl.stdin.take().unwrap().write_all(stats.as_pushgateway(&self.job).as_bytes())?;
+        let status = curl.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("curl exited with {}", status)))
+        }
+    }
+
+    /// Pushes `stats` from another thread, logging failures. A push is skipped
+    /// while the previous one is still running, ex: against a slow gateway.
+    fn push_in_background(&self, stats: Statistics) {
+        if self.in_flight.swap(true, Ordering::AcqRel) {
+            debug!("Skipping Pushgateway push, the previous one is still running");
+            return;
+        }
+        let pushgateway = Pushgateway {
+            url: self.url.clone(),
+            job: self.job.clone(),
+            in_flight: self.in_flight.clone(),
+        };
+        thread::spawn(move || {
+            if let Err(e) = pushgateway.push(&stats) {
+                warn!("Couldn't push statistics to the Pushgateway: {}", e);
+            }
+            pushgateway.in_flight.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+fn encode_path_segment(segment: &str) -> String {
+    segment.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Append-only file of the requests handed to the collector but not yet
+/// committed, one `ingest_jsonl` line each, so they survive a crash.
+pub struct RequestQueue {
+    path: PathBuf,
+    file: File,
+    /// Requests a previous run left at the front of the file, until recovered
+    leftover: usize,
+}
+
+impl RequestQueue {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RequestQueue, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let leftover = BufReader::new(File::open(&path)?).lines().count();
+        Ok(RequestQueue { path, file, leftover })
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), io::Error> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")
+    }
+
+    /// Drops the `count` oldest requests, once they are committed. The rest
+    /// is rewritten into a temporary file that replaces the queue in one
+    /// rename, so a crash leaves either the old or the new queue behind; the
+    /// lock is only held while requests appended meanwhile are copied over.
+    fn discard_front(queue: &Mutex<RequestQueue>, count: usize) -> Result<(), io::Error> {
+        if count == 0 {
+            return Ok(());
+        }
+        let lock = || queue.lock().unwrap_or_else(|e| e.into_inner());
+        let (path, len) = {
+            let queue = lock();
+            (queue.path.clone(), queue.file.metadata()?.len())
+        };
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut rest = BufReader::new(File::open(&path)?.take(len));
+        let mut line = Vec::new();
+        for _ in 0..count {
+            line.clear();
+            if rest.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+        }
+        let mut tmp = File::create(&tmp_path)?;
+        io::copy(&mut rest, &mut tmp)?;
+
+        let mut queue = lock();
+        let mut appended = File::open(&path)?;
+        appended.seek(SeekFrom::Start(len))?;
+        io::copy(&mut appended, &mut tmp)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        queue.file = OpenOptions::new().append(true).open(&path)?;
+        Ok(())
+    }
+
+    /// Records the requests a previous run left behind. They stay at the
+    /// front of the queue until `discard_front` drops them, as requests
+    /// queued since may follow.
+    pub fn recover(&mut self, db: &Database) -> Result<usize, StatsError> {
+        let mut rest = BufReader::new(File::open(&self.path)?);
+        let mut front = Vec::new();
+        for _ in 0..self.leftover {
+            if rest.read_until(b'\n', &mut front)? == 0 {
+                break;
+            }
+        }
+        let recovered = db.ingest_jsonl(&front[..])?;
+        self.leftover = 0;
+        Ok(recovered)
+    }
+}
+
+fn queue_line(req: &CargoRequest) -> String {
+    json!({
+        "name": req.name,
+        "version": req.version,
+        "hit": req.hit,
+        "size": req.size,
+        "client": req.client,
+        "is_range": req.is_range,
+        "requested_by": req.requested_by,
+        "bypass": req.bypass,
+        "fetch_ms": req.fetch_ms,
+        "upstream_down": req.upstream_down,
+        "failed": req.failed,
+        "target": req.target,
+        "time": humantime::format_rfc3339_millis(req.received_at.unwrap_or_else(SystemTime::now))
+            .to_string()
+    }).to_string()
+}
+
+/// Hands requests to the collector without ever blocking crate serving;
+/// requests that don't fit in the channel are counted as dropped.
+#[derive(Clone)]
+pub struct StatsSender {
+    sender: SyncSender<CargoRequest>,
+    dropped: Arc<AtomicU64>,
+    commit_latency: Arc<Mutex<CommitLatency>>,
+    queue: Option<Arc<Mutex<RequestQueue>>>,
+    degraded: Arc<AtomicBool>,
+}
+
+impl StatsSender {
+    pub fn send(&self, req: CargoRequest) {
+        let req = CargoRequest { received_at: Some(SystemTime::now()), ..req };
+        // Queueing and sending under one lock keeps the file in channel order.
+        let mut queue = self.queue
+            .as_ref()
+            .map(|queue| queue.lock().unwrap_or_else(|e| e.into_inner()));
+        let line = queue.as_ref().map(|_| queue_line(&req));
+        let queued = match self.sender.try_send(req) {
+            Ok(()) => true,
+            // Without a collector the request waits in the queue for the next start.
+            Err(TrySendError::Disconnected(_)) => queue.is_some(),
+            Err(e) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Dropped stats event ({} in total): {:?}", dropped, e);
+                false
+            }
+        };
+        if let (true, Some(queue), Some(line)) = (queued, queue.as_mut(), line) {
+            if let Err(e) = queue.append(&line) {
+                warn!("Couldn't queue stats event: {}", e);
+            }
+        }
+    }
+
+    /// Requests dropped over the lifetime of the stats database.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Latency of the collector's commits since it started.
+    pub fn commit_latency(&self) -> CommitLatency {
+        *self.commit_latency.lock().unwrap()
+    }
+
+    /// Whether the last write to the stats database failed, so recent
+    /// requests are only counted in memory and missing from reports.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+}
+
+struct Collector {
+    /// `None` while the database can't be opened; every flush tries again
+    db: Option<Database>,
+    /// Where the database is opened from, `Database::new`'s default if `None`
+    db_path: Option<String>,
+    /// Only every `sample_rate`th request is recorded, counting for
+    /// `sample_rate` requests. Totals are then estimates: small crates may be
+    /// missed entirely or over-counted, while high-volume figures stay close.
+    sample_rate: u32,
+    received: u64,
+    flush_interval: Duration,
+    aggregate: Aggregate,
+    log_summary: RequestLogSummary,
+    watchdog: Option<HitRatioWatchdog>,
+    pushgateway: Option<Pushgateway>,
+    dropped: Arc<AtomicU64>,
+    persisted_dropped: u64,
+    commit_latency: Arc<Mutex<CommitLatency>>,
+    queue: Option<Arc<Mutex<RequestQueue>>>,
+    /// Requests received since the queue was last trimmed
+    uncommitted: usize,
+    degraded: Arc<AtomicBool>,
+}
+
+impl Collector {
+    /// Opens the database, recording what a previous run left in the queue
+    /// and when this one started.
+    fn open_db(&mut self) -> Result<Database, StatsError> {
+        let db = Database::new(self.db_path.as_deref(), None)?;
+        if let Some(ref queue) = self.queue {
+            let leftover = {
+                let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+                let leftover = queue.leftover;
+                let recovered = queue.recover(&db)?;
+                if recovered > 0 {
+                    info!("Recovered {} queued stats events", recovered);
+                }
+                leftover
+            };
+            if let Err(e) = RequestQueue::discard_front(queue, leftover) {
+                warn!("Couldn't trim the stats queue: {}", e);
+                self.uncommitted += leftover;
+            }
+        }
+        if let Err(e) = db.set_meta_now(STARTED_AT) {
+            warn!("Couldn't record the collector start time: {}", e);
+        }
+        self.persisted_dropped = db.dropped_events().unwrap_or(0);
+        self.dropped.fetch_add(self.persisted_dropped, Ordering::Relaxed);
+        Ok(db)
+    }
+
+    /// Opens the database as the collector starts; without one it starts out
+    /// degraded, counting requests in memory.
+    fn start(&mut self) {
+        match self.open_db() {
+            Ok(db) => self.db = Some(db),
+            Err(e) => {
+                warn!("Couldn't open the statistics database, keeping statistics in memory \
+                       until it opens: {}",
+                      e);
+                self.degraded.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn write_out(&mut self) -> Result<(), StatsError> {
+        if self.db.is_none() {
+            let db = self.open_db()?;
+            self.db = Some(db);
+        }
+        let db = self.db.as_ref().expect("opened above");
+        if !self.aggregate.pending.is_empty() {
+            let started = Instant::now();
+            self.aggregate.flush(db)?;
+            self.commit_latency.lock().unwrap().record(started.elapsed());
+        }
+        if let Some(ref queue) = self.queue {
+            match RequestQueue::discard_front(queue, self.uncommitted) {
+                Ok(()) => self.uncommitted = 0,
+                Err(e) => warn!("Couldn't trim the stats queue: {}", e),
+            }
+        }
+        db.set_meta_now(LAST_FLUSH_AT)?;
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped != self.persisted_dropped {
+            db.set_meta(DROPPED_EVENTS, &dropped.to_string())?;
+            self.persisted_dropped = dropped;
+        }
+        Ok(())
+    }
+
+    fn run(mut self, receiver: Receiver<CargoRequest>) {
+        let mut last_flush = Instant::now();
+        let mut last_watchdog_check = Instant::now();
+        loop {
+            let timeout = self.flush_interval.checked_sub(last_flush.elapsed())
+                .unwrap_or_default();
+            match receiver.recv_timeout(timeout) {
+                Ok(req) => {
+                    self.log_summary.record(&req);
+                    self.received += 1;
+                    self.uncommitted += 1;
+                    if self.received.is_multiple_of(u64::from(self.sample_rate)) {
+                        if self.aggregate.is_full() {
+                            self.flush();
+                        }
+                        if !self.aggregate.add(req, i64::from(self.sample_rate)) {
+                            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                            debug!("Dropped stats event ({} in total): collector backlog full",
+                                   dropped);
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    break;
+                }
+            }
+            if last_flush.elapsed() >= self.flush_interval {
+                self.flush();
+                last_flush = Instant::now();
+            }
+            if let (Some(watchdog), Some(db)) = (self.watchdog.as_ref(), self.db.as_ref()) {
+                if last_watchdog_check.elapsed() >= WATCHDOG_INTERVAL {
+                    watchdog.check(db);
+                    last_watchdog_check = Instant::now();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        // Requests stay counted in memory while the database can't be written,
+        // ex: when its disk is full, and go out with the first flush that works.
+        match self.write_out() {
+            Ok(()) => {
+                if self.degraded.swap(false, Ordering::Relaxed) {
+                    info!("Statistics database is writable again");
+                }
+            }
+            Err(e) => {
+                if !self.degraded.swap(true, Ordering::Relaxed) {
+                    warn!("Couldn't write statistics, keeping them in memory until it works: {}",
+                          e);
+                }
+            }
+        }
+        if let (Some(pushgateway), Some(db)) = (self.pushgateway.as_ref(), self.db.as_ref()) {
+            pushgateway.push_in_background(db.stats());
+        }
+    }
+}
+
+pub fn stat_collector(config: &Config) -> StatsSender {
+    let (sender, receiver) = sync_channel::<CargoRequest>(CHANNEL_CAPACITY);
+    let queue = config.stats_queue_path.as_ref().and_then(|path| {
+        match RequestQueue::open(path) {
+            Ok(queue) => Some(Arc::new(Mutex::new(queue))),
+            Err(e) => {
+                warn!("Couldn't open the stats queue {}: {}", path, e);
+                None
+            }
+        }
+    });
+    let dropped = Arc::new(AtomicU64::new(0));
+    let commit_latency = Arc::new(Mutex::new(CommitLatency::default()));
+    let degraded = Arc::new(AtomicBool::new(false));
+    let mut collector = Collector {
+        db: None,
+        db_path: None,
+        sample_rate: config.stats_sample_rate.max(1),
+        received: 0,
+        flush_interval: config.stats_flush_interval,
+        aggregate: Aggregate::new(),
+        log_summary: RequestLogSummary::new(),
+        watchdog: config.hit_ratio_alert.map(HitRatioWatchdog::new),
+        pushgateway: config.pushgateway_url
+            .as_ref()
+            .map(|url| Pushgateway::new(url.as_str(), config.pushgateway_job.as_str())),
+        dropped: dropped.clone(),
+        persisted_dropped: 0,
+        commit_latency: commit_latency.clone(),
+        queue: queue.clone(),
+        uncommitted: 0,
+        degraded: degraded.clone(),
+    };
+    collector.start();
+    thread::spawn(move || collector.run(receiver));
+    StatsSender {
+        sender,
+        dropped,
+        commit_latency,
+        queue,
+        degraded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::process;
+    use std::sync::Once;
+    use log::{Log, Metadata};
+    use log::kv::{self, Key, Value, Visitor};
+    use rusqlite::Connection;
+
+    const NOW: &str = "2020-01-10 12:00:00.000";
+
+    fn test_db() -> Database {
+        let mut db = Database::from_connection(Connection::open_in_memory().unwrap(), None)
+            .unwrap();
+        db.set_clock(FixedClock(NOW.into()));
+        db
+    }
+
+    fn request(name: &str, version: &str, hit: bool, size: i64) -> CargoRequest {
+        CargoRequest {
+            name: name.into(),
+            version: version.into(),
+            hit,
+            size,
+            ..Default::default()
+        }
+    }
+
+    fn totals(stats: &Statistics) -> (i64, i64, i64, i64) {
+        (stats.downloads, stats.hits, stats.misses, stats.bandwidth_saved)
+    }
+
+    thread_local! {
+        static LOGGED: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+        static FIELDS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Keeps the records logged on each thread for `logged_by` to collect.
+    struct CaptureLogger;
+
+    struct CaptureFields;
+
+    impl<'kvs> Visitor<'kvs> for CaptureFields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            FIELDS.with(|fields| fields.borrow_mut().push((key.to_string(), value.to_string())));
+            Ok(())
+        }
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LOGGED.with(|logged| {
+                logged.borrow_mut().push((record.level(), record.args().to_string()))
+            });
+            record.key_values().visit(&mut CaptureFields).unwrap();
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Records logged on this thread while running `f`.
+    fn logged_by<F: FnOnce()>(f: F) -> Vec<(Level, String)> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+        FIELDS.with(|fields| fields.borrow_mut().clear());
+        f();
+        LOGGED.with(|logged| logged.replace(Vec::new()))
+    }
+
+    /// Structured fields of the records captured by the last `logged_by`.
+    fn logged_fields() -> Vec<(String, String)> {
+        FIELDS.with(|fields| fields.borrow().clone())
+    }
+
+    #[test]
+    fn dump_sql_replays_into_a_fresh_database() {
+        let db = test_db();
+        db.add_request("it's-quoted", "1.0.0", true, 10).unwrap();
+        db.add_request("serde", "1.0.104", false, 20).unwrap();
+        db.add_requests(&request("serde", "1.0.104", true, 20), 3).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&db.dump_sql().unwrap()).unwrap();
+        let mut replayed = Database::from_connection(conn, None).unwrap();
+        replayed.set_clock(FixedClock(NOW.into()));
+
+        assert_eq!(totals(&replayed.stats()), totals(&db.stats()));
+        assert_eq!(replayed.crates().unwrap(), db.crates().unwrap());
+    }
+
+    #[test]
+    fn dumped_reals_read_back_as_the_same_value() {
+        let conn = Connection::open_in_memory().unwrap();
+        for &f in &[1.5, -0.25, f64::INFINITY, f64::NEG_INFINITY] {
+            let literal = sql_literal(ValueRef::Real(f));
+            let read: f64 = conn
+                .query_row(&format!("SELECT {}", literal), params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(read, f, "{}", literal);
+        }
+        assert_eq!(sql_literal(ValueRef::Real(f64::NAN)), "NULL");
+    }
+
+    #[test]
+    fn request_log_summary_bounds_info_lines() {
+        let logged = logged_by(|| {
+            let mut summary = RequestLogSummary::new();
+            for _ in 0..1000 {
+                summary.record(&request("serde", "1.0.104", true, 10));
+            }
+        });
+        let count = |level| logged.iter().filter(|&&(l, _)| l == level).count();
+        assert!(count(Level::Info) <= 1);
+        assert_eq!(count(Level::Trace), 1000);
+    }
+
+    /// `NOW` shifted by an SQLite date modifier, ex: `-3 days`.
+    fn ago(modifier: &str) -> String {
+        Connection::open_in_memory()
+            .unwrap()
+            .query_row("SELECT datetime($1, $2)", params![NOW, modifier], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn declining_crates_reports_only_crates_that_fell() {
+        let db = test_db();
+        let falling = request("falling", "1.0.0", true, 1);
+        let steady = request("steady", "1.0.0", true, 1);
+        db.insert_requests(&falling, 10, &ago("-9 days")).unwrap();
+        db.insert_requests(&falling, 2, &ago("-1 days")).unwrap();
+        db.insert_requests(&steady, 5, &ago("-9 days")).unwrap();
+        db.insert_requests(&steady, 5, &ago("-1 days")).unwrap();
+
+        assert_eq!(db.declining_crates(50.0).unwrap(), vec![("falling".to_string(), 10, 2)]);
+        assert!(db.declining_crates(101.0).is_err());
+    }
+
+    #[test]
+    fn from_connection_keeps_custom_pragmas() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "cache_size", &-4096).unwrap();
+        let db = Database::from_connection(conn, None).unwrap();
+
+        let cache_size: i64 = db.conn.pragma_query_value(None, "cache_size", |row| row.get(0))
+            .unwrap();
+        assert_eq!(cache_size, -4096);
+        db.add_request("serde", "1.0.104", false, 10).unwrap();
+        assert_eq!(db.stats().downloads, 1);
+    }
+
+    #[test]
+    fn peak_hour_finds_the_spike() {
+        let db = test_db();
+        let req = request("serde", "1.0.104", true, 1);
+        let yesterday = &ago("-1 days")[..10];
+        let at = |time: &str| format!("{} {}", yesterday, time);
+        db.insert_requests(&req, 3, &at("08:15:00")).unwrap();
+        db.insert_requests(&req, 9, &at("09:05:00")).unwrap();
+        db.insert_requests(&req, 4, &at("09:55:00")).unwrap();
+        db.insert_requests(&req, 5, &at("10:30:00")).unwrap();
+
+        assert_eq!(db.peak_hour("2 days").unwrap(), (at("09:00:00"), 13));
+    }
+
+    #[test]
+    fn checksum_mismatch_detects_a_different_checksum() {
+        let db = test_db();
+        assert!(!db.checksum_mismatch("serde", "1.0.104", "abc").unwrap());
+        db.record_checksum("serde", "1.0.104", "abc").unwrap();
+        // The first recorded checksum is kept.
+        db.record_checksum("serde", "1.0.104", "def").unwrap();
+
+        assert!(!db.checksum_mismatch("serde", "1.0.104", "abc").unwrap());
+        assert!(db.checksum_mismatch("serde", "1.0.104", "def").unwrap());
+    }
+
+    fn download_rows(db: &Database) -> i64 {
+        db.conn.query_row("SELECT count(*) FROM downloads", params![], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn aggregate_folds_repeated_requests_into_few_rows() {
+        let db = test_db();
+        let mut aggregate = Aggregate::new();
+        for _ in 0..100 {
+            assert!(aggregate.add(request("serde", "1.0.104", true, 10), 1));
+            assert!(aggregate.add(request("rand", "0.7.3", false, 5), 1));
+        }
+        aggregate.flush(&db).unwrap();
+
+        assert_eq!(download_rows(&db), 2);
+        assert_eq!(totals(&db.stats()), (200, 100, 100, 1000));
+        assert!(aggregate.pending.is_empty());
+    }
+
+    #[test]
+    fn aggregate_keeps_each_crates_requests_in_arrival_order() {
+        let db = test_db();
+        let mut aggregate = Aggregate::new();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_578_657_600); // 2020-01-10 12:00
+        for (i, &hit) in [true, false, true, true].iter().enumerate() {
+            let received_at = start - Duration::from_secs(60 * (4 - i as u64));
+            let req = CargoRequest {
+                received_at: Some(received_at),
+                ..request("serde", "1", hit, 1)
+            };
+            aggregate.add(req, 1);
+        }
+        aggregate.flush(&db).unwrap();
+
+        let rows = db.conn
+            .prepare("SELECT time, hit, count FROM downloads ORDER BY rowid")
+            .unwrap()
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, bool, i64)>, _>>()
+            .unwrap();
+        assert_eq!(rows,
+                   vec![("2020-01-10 11:56:00.000".to_string(), true, 1),
+                        ("2020-01-10 11:57:00.000".to_string(), false, 1),
+                        ("2020-01-10 11:58:00.000".to_string(), true, 2)]);
+    }
+
+    #[test]
+    fn full_aggregate_only_merges_into_existing_rows() {
+        let mut aggregate = Aggregate::new();
+        for i in 0..MAX_PENDING_ROWS {
+            assert!(aggregate.add(request(&format!("crate-{}", i), "1", true, 1), 1));
+        }
+        assert!(aggregate.is_full());
+        assert!(aggregate.add(request("crate-0", "1", true, 1), 1));
+        assert!(!aggregate.add(request("crate-0", "1", false, 1), 1));
+        assert_eq!(aggregate.pending.len(), MAX_PENDING_ROWS);
+    }
+
+    #[test]
+    fn diagnostics_describe_an_in_memory_database() {
+        let db = test_db();
+        db.add_request("serde", "1.0.104", true, 10).unwrap();
+        let diagnostics = db.diagnostics();
+
+        assert_eq!(diagnostics.sqlite_version, rusqlite::version());
+        assert_eq!(diagnostics.path, ":memory:");
+        assert_eq!(diagnostics.user_version, SCHEMA_VERSION);
+        assert!(diagnostics.table_rows.contains(&("downloads".to_string(), 1)));
+        assert!(diagnostics.table_rows.contains(&("crates".to_string(), 1)));
+    }
+
+    fn from_client(name: &str, client: &str) -> CargoRequest {
+        CargoRequest { client: Some(client.into()), ..request(name, "1.0.0", true, 1) }
+    }
+
+    #[test]
+    fn single_client_crates_skips_shared_crates() {
+        let db = test_db();
+        db.add_requests(&from_client("team-only", "10.0.0.1"), 3).unwrap();
+        db.add_requests(&from_client("shared", "10.0.0.1"), 1).unwrap();
+        db.add_requests(&from_client("shared", "10.0.0.2"), 1).unwrap();
+
+        assert_eq!(db.single_client_crates("1 day").unwrap(), vec!["team-only".to_string()]);
+    }
+
+    #[test]
+    fn stream_ndjson_writes_one_object_per_row() {
+        let db = test_db();
+        db.add_request("serde", "1.0.104", true, 10).unwrap();
+        db.add_request("rand", "0.7.3", false, 20).unwrap();
+        db.insert_requests(&request("old", "1.0.0", true, 5), 1, "2019-01-01").unwrap();
+        let mut out = Vec::new();
+        db.stream_ndjson(&mut out, "1 day").unwrap();
+
+        let lines = String::from_utf8(out).unwrap();
+        let records = lines.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter()
+            .any(|r| r["crate"] == "serde" && r["hit"] == true && r["size"] == 10));
+        assert!(records.iter().all(|r| r["version"].is_string() && r["time"].is_string()));
+    }
+
+    #[test]
+    fn range_requests_only_count_the_transferred_bytes() {
+        let db = test_db();
+        db.add_request("serde", "1.0.104", true, 1000).unwrap();
+        let range = CargoRequest { is_range: true, ..request("serde", "1.0.104", true, 100) };
+        db.add_requests(&range, 1).unwrap();
+
+        assert_eq!(db.range_requests("1 day"), 1);
+        assert_eq!(db.bandwidth_saved("1 day"), 1100);
+        // The file size comes from full downloads only.
+        assert_eq!(db.cached_bytes(), 1000);
+    }
+
+    #[test]
+    fn snapshots_are_read_back_in_order() {
+        let mut db = test_db();
+        db.add_request("serde", "1.0.104", true, 10).unwrap();
+        db.snapshot().unwrap();
+        db.set_clock(FixedClock("2020-01-10 13:00:00".into()));
+        db.add_request("serde", "1.0.104", false, 10).unwrap();
+        db.snapshot().unwrap();
+
+        let snapshots = db.snapshots_between("2020-01-10 00:00:00", "2020-01-10 23:59:59")
+            .unwrap()
+            .into_iter()
+            .map(|(time, stats)| (time, totals(&stats)))
+            .collect::<Vec<_>>();
+        assert_eq!(snapshots,
+                   vec![("2020-01-10 12:00:00".to_string(), (1, 1, 0, 10)),
+                        ("2020-01-10 13:00:00".to_string(), (2, 1, 1, 10))]);
+        assert!(db.snapshots_between("2020-01-11", "2020-01-12").unwrap().is_empty());
+    }
+
+    #[test]
+    fn crate_names_are_case_insensitive() {
+        let db = test_db();
+        db.add_request("Serde", "1.0.104", false, 10).unwrap();
+        db.add_request("serde", "1.0.104", true, 10).unwrap();
+        db.add_request("SERDE", "1.0.105", true, 10).unwrap();
+
+        assert_eq!(db.crate_id("Serde"), db.crate_id("serde"));
+        assert_eq!(db.crates().unwrap(),
+                   vec![Crate {
+                            name: "serde".into(),
+                            versions: 2,
+                            downloads: 3,
+                        }]);
+        let duplicate = db.conn.execute("INSERT INTO crates (name) VALUES ('SeRdE')", params![]);
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn watchdog_fires_below_the_threshold() {
+        let db = test_db();
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let fired = alerts.clone();
+        let watchdog = HitRatioWatchdog::with_alert(0.5, move |ratio| {
+            fired.lock().unwrap().push(ratio)
+        });
+        assert_eq!(watchdog.check(&db), None);
+
+        db.add_requests(&request("serde", "1.0.104", false, 10), 4).unwrap();
+        assert_eq!(watchdog.check(&db), Some(0.0));
+        db.add_requests(&request("serde", "1.0.104", true, 10), 6).unwrap();
+        assert_eq!(watchdog.check(&db), None);
+        assert_eq!(*alerts.lock().unwrap(), vec![0.0]);
+    }
+
+    #[test]
+    fn crates_with_activity_ages_the_last_request() {
+        let db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 10), 1, "2020-01-10 10:00:00")
+            .unwrap();
+        db.insert_requests(&request("serde", "1.0.104", true, 10), 1, "2020-01-10 11:59:00")
+            .unwrap();
+        db.record_cached("prewarmed", "0.1.0").unwrap();
+
+        let ages = db.crates_with_activity()
+            .unwrap()
+            .into_iter()
+            .map(|(krate, age)| (krate.name, age))
+            .collect::<Vec<_>>();
+        assert_eq!(ages, vec![("prewarmed".to_string(), None), ("serde".to_string(), Some(60))]);
+    }
+
+    #[test]
+    fn crate_letter_index_buckets_by_first_letter() {
+        let db = test_db();
+        for name in &["serde", "syn", "rand", "Regex", "7z", "_private"] {
+            db.record_cached(name, "1.0.0").unwrap();
+        }
+
+        assert_eq!(db.crate_letter_index().unwrap(), vec![('#', 2), ('R', 2), ('S', 2)]);
+    }
+
+    #[test]
+    fn validation_and_database_failures_are_distinct_errors() {
+        let db = test_db();
+        match db.add_request("", "1.0.0", true, 1) {
+            Err(StatsError::Validation(_)) => {}
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        db.conn.execute_batch("DROP TABLE downloads").unwrap();
+        match db.peak_hour("1 day") {
+            Err(StatsError::Sqlite(_)) => {}
+            other => panic!("expected a database error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn popularity_gini_measures_skew() {
+        let even = test_db();
+        let skewed = test_db();
+        for i in 0..100 {
+            let name = format!("crate-{}", i);
+            even.add_requests(&request(&name, "1.0.0", true, 1), 10).unwrap();
+            skewed.add_requests(&request(&name, "1.0.0", true, 1), if i == 0 { 10_000 } else { 1 })
+                .unwrap();
+        }
+
+        assert!(even.popularity_gini("1 day").abs() < 1e-9);
+        assert!(skewed.popularity_gini("1 day") > 0.9);
+        assert_eq!(test_db().popularity_gini("1 day"), 0.0);
+    }
+
+    /// A database file path unique to this test run; the file is removed first.
+    fn temp_db_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("cargo-cacher-{}-{}.sqlite", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn test_collector(db: Database) -> Collector {
+        Collector {
+            db: Some(db),
+            db_path: None,
+            sample_rate: 1,
+            received: 0,
+            flush_interval: Duration::from_secs(5),
+            aggregate: Aggregate::new(),
+            log_summary: RequestLogSummary::new(),
+            watchdog: None,
+            pushgateway: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+            persisted_dropped: 0,
+            commit_latency: Arc::new(Mutex::new(CommitLatency::default())),
+            queue: None,
+            uncommitted: 0,
+            degraded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn dropped_events_survive_a_restart() {
+        let path = temp_db_path("dropped-events");
+        let mut collector = test_collector(Database::new(Some(path.as_str()), None).unwrap());
+        collector.dropped.fetch_add(3, Ordering::Relaxed);
+        collector.write_out().unwrap();
+        drop(collector);
+
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(db.dropped_events().unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn who_pulls_lists_the_parents_of_a_child() {
+        let db = test_db();
+        for parent in &["tokio", "hyper", "tokio"] {
+            let req = CargoRequest {
+                requested_by: Some(parent.to_string()),
+                ..request("bytes", "0.5.4", true, 1)
+            };
+            db.add_requests(&req, 1).unwrap();
+        }
+        db.add_request("bytes", "0.5.4", true, 1).unwrap();
+
+        assert_eq!(db.who_pulls("Bytes").unwrap(), vec!["hyper".to_string(), "tokio".to_string()]);
+        assert!(db.who_pulls("tokio").unwrap().is_empty());
+    }
+
+    fn download_times(db: &Database) -> Vec<String> {
+        db.conn
+            .prepare("SELECT time FROM downloads ORDER BY time")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn requests_seconds_apart_get_distinct_timestamps() {
+        let mut db = test_db();
+        db.set_clock(FixedClock("2020-01-10 11:59:58.500".into()));
+        db.add_request("serde", "1.0.104", true, 1).unwrap();
+        db.set_clock(FixedClock("2020-01-10 12:00:01.250".into()));
+        db.add_request("serde", "1.0.104", true, 1).unwrap();
+
+        assert_eq!(download_times(&db),
+                   vec!["2020-01-10 11:59:58.500".to_string(),
+                        "2020-01-10 12:00:01.250".to_string()]);
+        let histogram = db.hourly_histogram("1 day").unwrap();
+        assert_eq!((histogram[11], histogram[12]), ((11, 1), (12, 1)));
+    }
+
+    #[test]
+    fn windows_subtract_the_interval() {
+        let db = test_db();
+        db.insert_requests(&request("recent", "1.0.0", true, 10), 1, &ago("-1 hours"))
+            .unwrap();
+        db.insert_requests(&request("old", "1.0.0", false, 10), 1, &ago("-2 days"))
+            .unwrap();
+
+        assert_eq!(totals(&db.stats_in("24 hours")), (1, 1, 0, 10));
+        assert_eq!(db.downloads("24 hours"), 1);
+        assert_eq!(db.downloads("3 days"), 2);
+    }
+
+    #[test]
+    fn sampling_stores_one_in_n_requests_and_scales_totals() {
+        let path = temp_db_path("sampling");
+        let mut collector = test_collector(Database::new(Some(path.as_str()), None).unwrap());
+        collector.sample_rate = 10;
+        let (sender, receiver) = sync_channel(1000);
+        for i in 0..1000 {
+            let req = request(&format!("crate-{}", i), "1.0.0", i % 3 == 0, 100);
+            sender.send(req).unwrap();
+        }
+        drop(sender);
+        collector.run(receiver);
+
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(download_rows(&db), 100);
+        let (downloads, hits, misses, bandwidth_saved) = totals(&db.stats());
+        assert_eq!(downloads, 1000);
+        assert!((hits - 333).abs() <= 50, "{} hits", hits);
+        assert_eq!(hits + misses, 1000);
+        assert_eq!(bandwidth_saved, hits * 100);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inter_arrival_median_of_evenly_spaced_requests() {
+        let db = test_db();
+        let req = request("serde", "1.0.104", true, 1);
+        for seconds in &[90, 60, 30, 0] {
+            db.insert_requests(&req, 1, &ago(&format!("-{} seconds", seconds))).unwrap();
+        }
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, &ago("-1 hours")).unwrap();
+
+        let median = db.inter_arrival_median("serde", "1 day").unwrap().unwrap();
+        assert!((median - 30.0).abs() < 1e-3, "median gap {}", median);
+        assert_eq!(db.inter_arrival_median("rand", "1 day").unwrap(), None);
+    }
+
+    #[test]
+    fn database_new_defaults_to_the_environment() {
+        let path = temp_db_path("env-default");
+        env::set_var(STATS_DB_ENV, &path);
+        let db = Database::new(None::<&str>, None);
+        env::remove_var(STATS_DB_ENV);
+
+        db.unwrap().add_request("serde", "1.0.104", true, 1).unwrap();
+        let reopened = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(reopened.diagnostics().path, path);
+        assert_eq!(download_rows(&reopened), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn forecast_extends_the_daily_trend() {
+        let steady = test_db();
+        let rising = test_db();
+        for age in 1..=FORECAST_HISTORY_DAYS as i64 {
+            let time = ago(&format!("-{} days", age));
+            steady.insert_requests(&request("serde", "1.0.104", true, 1000), 1, &time).unwrap();
+            let saved = 100 * (FORECAST_HISTORY_DAYS as i64 + 1 - age);
+            rising.insert_requests(&request("serde", "1.0.104", true, saved), 1, &time).unwrap();
+        }
+        // Today's incomplete day is left out.
+        steady.add_request("serde", "1.0.104", true, 1_000_000).unwrap();
+
+        assert_eq!(steady.forecast_bandwidth_saved(7), 7000);
+        assert_eq!(rising.forecast_bandwidth_saved(2), 2900 + 3000);
+        assert_eq!(test_db().forecast_bandwidth_saved(30), 0);
+    }
+
+    #[test]
+    fn bypasses_count_as_downloads_but_not_misses() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", true, 10), 3).unwrap();
+        let bypass = CargoRequest { bypass: true, ..request("serde", "1.0.104", false, 10) };
+        db.add_requests(&bypass, 5).unwrap();
+
+        assert_eq!(db.bypasses("1 day"), 5);
+        assert_eq!(totals(&db.stats()), (8, 3, 0, 10));
+        assert_eq!(HitRatioWatchdog::with_alert(0.9, |_| {}).check(&db), None);
+    }
+
+    #[test]
+    fn worst_hit_ratio_crates_rank_the_busy_misser_first() {
+        let db = test_db();
+        db.add_requests(&request("missy", "1.0.0", false, 1), 40).unwrap();
+        db.add_requests(&request("missy", "1.0.0", true, 1), 10).unwrap();
+        db.add_requests(&request("healthy", "1.0.0", true, 1), 45).unwrap();
+        db.add_requests(&request("healthy", "1.0.0", false, 1), 5).unwrap();
+        db.add_requests(&request("one-off", "1.0.0", false, 1), 1).unwrap();
+
+        assert_eq!(db.worst_hit_ratio_crates("1 day", 10, 5).unwrap(),
+                   vec![("missy".to_string(), 0.2), ("healthy".to_string(), 0.9)]);
+        assert_eq!(db.worst_hit_ratio_crates("1 day", 1, 1).unwrap(),
+                   vec![("one-off".to_string(), 0.0)]);
+    }
+
+    #[test]
+    fn uptime_grows_while_the_start_time_stays() {
+        let mut db = test_db();
+        assert_eq!(db.uptime_secs().unwrap(), None);
+        db.set_meta_now(STARTED_AT).unwrap();
+        let started_at = db.started_at().unwrap();
+        assert_eq!(started_at, Some(NOW.to_string()));
+        assert_eq!(db.uptime_secs().unwrap(), Some(0));
+
+        db.set_clock(FixedClock("2020-01-10 12:01:30.000".into()));
+        assert_eq!(db.uptime_secs().unwrap(), Some(90));
+        assert_eq!(db.started_at().unwrap(), started_at);
+    }
+
+    #[test]
+    fn estimated_cost_saved_prices_gibibytes() {
+        let db = test_db();
+        db.add_request("serde", "1.0.104", true, 2 * 1024 * 1024 * 1024).unwrap();
+        db.add_request("serde", "1.0.104", false, 1024 * 1024 * 1024).unwrap();
+
+        assert!((db.estimated_cost_saved("1 day", 0.09) - 0.18).abs() < 1e-9);
+        assert_eq!(db.estimated_cost_saved("1 day", 0.0), 0.0);
+    }
+
+    #[test]
+    fn async_database_is_awaited_on_a_tokio_runtime() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let _entered = runtime.enter();
+        let db = AsyncDatabase::new(test_db());
+        runtime.block_on(db.add_request("serde", "1.0.104", true, 10)).unwrap();
+        runtime.block_on(db.add_request("serde", "1.0.104", false, 10)).unwrap();
+
+        assert_eq!(totals(&runtime.block_on(db.stats()).unwrap()), (2, 1, 1, 10));
+        assert_eq!(runtime.block_on(db.crates()).unwrap().len(), 1);
+        match runtime.block_on(db.run(|_| -> Result<(), StatsError> { panic!("lost") })) {
+            Err(StatsError::Task(_)) => {}
+            other => panic!("expected a task error, got {:?}", other),
+        }
+        assert_eq!(runtime.block_on(db.stats()).unwrap().downloads, 2);
+    }
+
+    #[test]
+    fn async_database_calls_outside_of_a_runtime_fail() {
+        let db = AsyncDatabase::new(test_db());
+        let call = db.add_request("serde", "1.0.104", true, 10);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        match runtime.block_on(call) {
+            Err(StatsError::NoRuntime) => {}
+            other => panic!("expected a missing runtime, got {:?}", other),
+        }
+        let _entered = runtime.enter();
+        assert_eq!(runtime.block_on(db.stats()).unwrap().downloads, 0);
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, version)| (name.to_string(), version.to_string())).collect()
+    }
+
+    #[test]
+    fn cached_yanked_versions_lists_yanked_versions() {
+        let db = test_db();
+        db.record_cached("serde", "1.0.103").unwrap();
+        db.record_cached("serde", "1.0.104").unwrap();
+        db.mark_yanked("serde", "1.0.103", true).unwrap();
+        db.mark_yanked("rand", "0.7.0", true).unwrap();
+        db.mark_yanked("rand", "0.7.0", false).unwrap();
+
+        assert_eq!(db.cached_yanked_versions().unwrap(), pairs(&[("serde", "1.0.103")]));
+    }
+
+    /// Polls `f` until it holds, for up to five seconds.
+    fn eventually<F: FnMut() -> bool>(mut f: F) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn buffered_requests_appear_once_the_flush_interval_elapses() {
+        let path = temp_db_path("flush-interval");
+        let mut collector = test_collector(Database::new(Some(path.as_str()), None).unwrap());
+        collector.flush_interval = Duration::from_millis(500);
+        let (sender, receiver) = sync_channel(16);
+        let collecting = thread::spawn(move || collector.run(receiver));
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+
+        sender.send(request("serde", "1.0.104", true, 10)).unwrap();
+        assert_eq!(db.downloads("1 hour"), 0);
+        assert!(eventually(|| db.downloads("1 hour") == 1));
+        assert!(eventually(|| db.last_flush_at().unwrap().is_some()));
+        let flushed_at = db.last_flush_at().unwrap();
+        assert!(eventually(|| db.last_flush_at().unwrap() > flushed_at));
+
+        drop(sender);
+        collecting.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn churn_ratio_contrasts_new_versions_with_repeats() {
+        let db = test_db();
+        for patch in 0..4 {
+            db.add_request("nightly-ish", format!("0.1.{}", patch), false, 1).unwrap();
+        }
+        db.add_request("nightly-ish", "0.1.3", true, 1).unwrap();
+        db.add_requests(&request("stable", "1.0.0", true, 1), 10).unwrap();
+
+        assert!((db.churn_ratio("nightly-ish", "1 day").unwrap() - 0.8).abs() < 1e-9);
+        assert!((db.churn_ratio("stable", "1 day").unwrap() - 0.1).abs() < 1e-9);
+        assert_eq!(db.churn_ratio("unknown", "1 day").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn merge_from_combines_overlapping_and_distinct_crates() {
+        let path = temp_db_path("merge-source");
+        {
+            let mut other =
+                Database::from_connection(Connection::open(&path).unwrap(), None).unwrap();
+            other.set_clock(FixedClock(NOW.into()));
+            other.add_requests(&request("serde", "1.0.104", true, 30), 3).unwrap();
+            other.add_requests(&request("rand", "0.7.3", false, 5), 1).unwrap();
+            let pulled = CargoRequest {
+                requested_by: Some("rand".to_string()),
+                ..request("log", "0.4.8", true, 0)
+            };
+            other.add_requests(&pulled, 1).unwrap();
+        }
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", false, 10), 1).unwrap();
+        db.add_requests(&request("log", "0.4.8", true, 20), 2).unwrap();
+
+        db.merge_from(&path).unwrap();
+
+        assert_eq!(totals(&db.stats_in("1 day")), (8, 6, 2, 50));
+        assert_eq!(db.who_pulls("log").unwrap(), ["rand"]);
+        let mut names: Vec<String> = db.crates().unwrap().into_iter().map(|c| c.name).collect();
+        names.sort();
+        assert_eq!(names, ["log", "rand", "serde"]);
+        let serde = db.stats_for_crates(&["serde".to_string()], "1 day").unwrap();
+        assert_eq!(totals(&serde), (4, 3, 1, 30));
+        assert_eq!(db.all_versions().unwrap().len(), 3);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn merge_from_a_missing_file_fails_without_creating_it() {
+        let path = temp_db_path("merge-missing");
+        let db = test_db();
+
+        match db.merge_from(&path) {
+            Err(StatsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            other => panic!("expected a missing file, got {:?}", other),
+        }
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn lru_crates_lists_the_oldest_accessed_versions_first() {
+        let db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-1 hour")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, &ago("-3 days")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, &ago("-2 hours")).unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 1), 1, &ago("-1 day")).unwrap();
+        db.record_cached("libc", "0.2.66").unwrap();
+
+        assert_eq!(db.lru_crates(3).unwrap(),
+                   pairs(&[("libc", "0.2.66"), ("log", "0.4.8"), ("rand", "0.7.3")]));
+        assert_eq!(db.lru_crates(10).unwrap().len(), 4);
+        assert!(db.lru_crates(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn logged_requests_carry_structured_fields() {
+        let mut summary = RequestLogSummary::new();
+        let logged = logged_by(|| summary.record(&request("serde", "1.0.104", true, 42)));
+
+        assert!(logged.iter().any(|&(level, ref message)| {
+            level == Level::Trace && message.contains("serde 1.0.104")
+        }));
+        assert_eq!(logged_fields(),
+                   pairs(&[("crate", "\"serde\""), ("version", "\"1.0.104\""), ("hit", "true"),
+                           ("size", "42")]));
+    }
+
+    #[test]
+    fn first_hit_delay_measures_from_the_first_miss() {
+        let db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", false, 1), 1, &ago("-2 hours")).unwrap();
+        db.insert_requests(&request("serde", "1.0.104", false, 1), 1, &ago("-1 hour")).unwrap();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-30 minutes")).unwrap();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-1 minutes")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", false, 1), 1, &ago("-1 hour")).unwrap();
+
+        let delay = db.first_hit_delay("serde").unwrap().unwrap();
+        assert!((delay - 5400.0).abs() < 1e-3);
+        assert_eq!(db.first_hit_delay("rand").unwrap(), None);
+        assert_eq!(db.first_hit_delay("unknown").unwrap(), None);
+    }
+
+    #[test]
+    fn table_prefixes_keep_tenants_apart() {
+        let path = temp_db_path("prefixes");
+        let open = |prefix| {
+            let mut db = Database::from_connection(Connection::open(&path).unwrap(), Some(prefix))
+                .unwrap();
+            db.set_clock(FixedClock(NOW.into()));
+            db
+        };
+        let prod = open("prod_");
+        let staging = open("staging_");
+        prod.add_requests(&request("serde", "1.0.104", true, 10), 2).unwrap();
+        staging.add_requests(&request("rand", "0.7.3", false, 5), 1).unwrap();
+
+        assert_eq!(totals(&prod.stats_in("1 day")), (2, 2, 0, 10));
+        assert_eq!(totals(&staging.stats_in("1 day")), (1, 0, 1, 0));
+        let pulled = CargoRequest {
+            requested_by: Some("serde".to_string()),
+            ..request("bytes", "0.5.4", true, 1)
+        };
+        prod.add_requests(&pulled, 1).unwrap();
+        prod.rename_crate("serde", "serde-renamed").unwrap();
+        assert_eq!(prod.who_pulls("bytes").unwrap(), ["serde-renamed"]);
+        assert!(prod.dump_sql().unwrap().contains("prod_downloads"));
+        assert!(!prod.dump_sql().unwrap().contains("staging_"));
+        match Database::from_connection(Connection::open_in_memory().unwrap(), Some("p; --")) {
+            Err(StatsError::Validation(_)) => {}
+            other => panic!("expected a validation error, got {:?}", other.map(|_| ())),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn delete_crate_removes_every_trace_of_one_crate() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.103", true, 10), 2).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 10), 1).unwrap();
+        db.add_requests(&request("serde", "1.0.104", true, 10), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 5), 1).unwrap();
+
+        assert_eq!(db.delete_crate("Serde").unwrap(), 6);
+
+        assert!(db.crate_id("serde").is_none());
+        assert_eq!(db.all_versions().unwrap(), pairs(&[("rand", "0.7.3")]));
+        assert_eq!(download_rows(&db), 1);
+        assert_eq!(totals(&db.stats_in("1 day")), (1, 1, 0, 5));
+        assert_eq!(db.delete_crate("serde").unwrap(), 0);
+    }
+
+    #[test]
+    fn top_k_share_measures_concentration() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", true, 1), 60).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 1), 20).unwrap();
+        for i in 0..10 {
+            db.add_requests(&request(&format!("tail-{}", i), "1.0.0", true, 1), 2).unwrap();
+        }
+
+        assert!((db.top_k_share(1, "1 day").unwrap() - 0.6).abs() < 1e-9);
+        assert!((db.top_k_share(2, "1 day").unwrap() - 0.8).abs() < 1e-9);
+        assert!((db.top_k_share(100, "1 day").unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(db.top_k_share(0, "1 day").unwrap(), 0.0);
+        assert_eq!(test_db().top_k_share(5, "1 day").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn stats_uses_the_configured_default_window() {
+        let mut db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 10), 1, &ago("-1 hour")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 10), 1, &ago("-3 days")).unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 10), 1, &ago("-10 days")).unwrap();
+        assert_eq!(db.stats().downloads, 1);
+
+        db.set_default_window("7 days");
+
+        assert_eq!(totals(&db.stats()), totals(&db.stats_in("7 days")));
+        assert_eq!(db.stats().downloads, 2);
+    }
+
+    #[test]
+    fn zero_size_requests_lists_only_empty_downloads() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", false, 0), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", false, 2048), 1).unwrap();
+
+        assert_eq!(db.zero_size_requests("1 day").unwrap(), pairs(&[("serde", "1.0.104")]));
+    }
+
+    #[test]
+    fn ingest_jsonl_skips_malformed_lines() {
+        let db = test_db();
+        let input = concat!(
+            r#"{"name": "serde", "version": "1.0.104", "hit": true, "size": 100}"#, "\n",
+            "not json\n",
+            r#"{"name": "rand", "version": "0.7.3", "hit": "yes"}"#, "\n",
+            "\n",
+            r#"{"name": "rand", "version": "0.7.3", "time": "2020-01-09 08:00:00"}"#, "\n",
+        );
+
+        let logged = logged_by(|| assert_eq!(db.ingest_jsonl(input.as_bytes()).unwrap(), 2));
+
+        assert_eq!(logged.iter().filter(|&&(level, _)| level == Level::Warn).count(), 2);
+        assert_eq!(totals(&db.stats_in("1 day")), (1, 1, 0, 100));
+        assert_eq!(download_times(&db), ["2020-01-09 08:00:00.000", NOW]);
+    }
+
+    #[test]
+    fn cumulative_bandwidth_series_only_grows() {
+        let db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 100), 1, &ago("-3 hours")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", false, 500), 1, &ago("-2 hours")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 50), 2, &ago("-2 hours")).unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 25), 1, &ago("-1 hour")).unwrap();
+
+        let series = db.cumulative_bandwidth_series("1 day", "hour").unwrap();
+
+        assert_eq!(series.len(), 3);
+        assert!(series.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(series.last().unwrap().1, db.bandwidth_saved_total());
+        assert_eq!(series.last().unwrap().1, 175);
+    }
+
+    #[test]
+    fn negative_sizes_are_recorded_as_zero() {
+        let db = test_db();
+        db.add_request("serde", "1.0.104", true, 100).unwrap();
+        let logged = logged_by(|| db.add_request("serde", "1.0.104", true, -1_000).unwrap());
+
+        assert!(logged.iter().any(|&(level, _)| level == Level::Warn));
+        let min_size: i64 =
+            db.conn.query_row("SELECT min(size) FROM downloads", params![], |row| row.get(0))
+                .unwrap();
+        assert_eq!(min_size, 0);
+        assert_eq!(db.bandwidth_saved_total(), 100);
+        assert_eq!(totals(&db.stats_in("1 day")), (2, 2, 0, 100));
+    }
+
+    #[test]
+    fn sorted_versions_orders_by_semver() {
+        let db = test_db();
+        for version in &["1.2.0", "1.10.0", "1.9.0", "1.10.0-beta.1", "snapshot"] {
+            db.record_cached("serde", version).unwrap();
+        }
+        db.record_cached("rand", "0.7.3").unwrap();
+
+        assert_eq!(db.sorted_versions("Serde").unwrap(),
+                   ["1.2.0", "1.9.0", "1.10.0-beta.1", "1.10.0", "snapshot"]);
+        assert!(db.sorted_versions("unknown").unwrap().is_empty());
+    }
+
+    #[test]
+    fn flushes_report_their_commit_latency() {
+        let mut collector = test_collector(test_db());
+        let latency = collector.commit_latency.clone();
+        collector.flush();
+        assert_eq!(latency.lock().unwrap().commits, 0);
+
+        for i in 0..3 {
+            for _ in 0..50 {
+                collector.aggregate.add(request(&format!("crate-{}", i), "1.0.0", true, 1), 1);
+            }
+            collector.flush();
+        }
+
+        let latency = *latency.lock().unwrap();
+        assert_eq!(latency.commits, 3);
+        assert!(latency.average > Duration::from_secs(0));
+        assert!(latency.max >= latency.average);
+        assert_eq!(download_rows(collector.db.as_ref().unwrap()), 3);
+    }
+
+    #[test]
+    fn diversity_ratio_is_low_for_repeats_and_high_for_churn() {
+        let repeats = test_db();
+        repeats.add_requests(&request("serde", "1.0.104", true, 1), 50).unwrap();
+        repeats.add_requests(&request("rand", "0.7.3", true, 1), 50).unwrap();
+        let churn = test_db();
+        for i in 0..10 {
+            churn.add_request("serde", format!("1.0.{}", i), false, 1).unwrap();
+        }
+
+        assert!((repeats.diversity_ratio("1 day").unwrap() - 0.02).abs() < 1e-9);
+        assert!((churn.diversity_ratio("1 day").unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(test_db().diversity_ratio("1 day").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn earliest_download_is_the_oldest_timestamp() {
+        let db = test_db();
+        assert_eq!(db.earliest_download().unwrap(), None);
+
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-1 hour")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, "2019-12-01 08:30:00").unwrap();
+        db.add_request("log", "0.4.8", true, 1).unwrap();
+
+        assert_eq!(db.earliest_download().unwrap(), Some("2019-12-01 08:30:00.000".to_string()));
+    }
+
+    #[test]
+    fn distinct_client_crate_pairs_counts_each_combination_once() {
+        let db = test_db();
+        db.add_requests(&from_client("serde", "10.0.0.1"), 3).unwrap();
+        let other_version = CargoRequest {
+            version: "1.0.1".into(),
+            ..from_client("serde", "10.0.0.1")
+        };
+        db.add_requests(&other_version, 1).unwrap();
+        db.add_requests(&from_client("serde", "10.0.0.2"), 1).unwrap();
+        db.add_requests(&from_client("rand", "10.0.0.1"), 1).unwrap();
+        db.add_requests(&request("log", "0.4.8", true, 1), 1).unwrap();
+
+        assert_eq!(db.distinct_client_crate_pairs("1 day").unwrap(), 3);
+    }
+
+    #[test]
+    fn miss_latency_histogram_buckets_fetch_times() {
+        let db = test_db();
+        let fetched = |hit, ms| {
+            CargoRequest { fetch_ms: Some(ms), ..request("serde", "1.0.104", hit, 1) }
+        };
+        for &(ms, count) in &[(10, 2), (100, 1), (250, 3), (5_000, 1)] {
+            db.add_requests(&fetched(false, ms), count).unwrap();
+        }
+        db.add_requests(&fetched(true, 10), 5).unwrap();
+        db.add_requests(&request("rand", "0.7.3", false, 1), 1).unwrap();
+
+        assert_eq!(db.miss_latency_histogram("1 day", &[100, 1_000]).unwrap(),
+                   [(100, 3), (1_000, 3), (i64::MAX, 1)]);
+        assert!(db.miss_latency_histogram("1 day", &[1_000, 100]).is_err());
+    }
+
+    #[test]
+    fn rename_crate_moves_the_stats_to_the_new_name() {
+        let db = test_db();
+        db.add_requests(&request("serde_derive_internals", "0.24.0", true, 10), 3).unwrap();
+        db.add_requests(&request("serde_derive_internals", "0.25.0", false, 10), 1).unwrap();
+        db.add_requests(&request("serde-internals", "0.25.0", true, 10), 2).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 1), 1).unwrap();
+
+        db.rename_crate("serde_derive_internals", "Serde-Internals").unwrap();
+
+        assert!(db.crate_id("serde_derive_internals").is_none());
+        let moved = db.stats_for_crates(&["serde-internals".to_string()], "1 day").unwrap();
+        assert_eq!(totals(&moved), (6, 5, 1, 20));
+        assert_eq!(db.all_versions().unwrap(),
+                   pairs(&[("rand", "0.7.3"),
+                           ("serde-internals", "0.24.0"),
+                           ("serde-internals", "0.25.0")]));
+        assert!(db.rename_crate("unknown", "other").is_err());
+    }
+
+    #[test]
+    fn a_fixed_clock_makes_windows_deterministic() {
+        let mut db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-86399 seconds"))
+            .unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, &ago("-86401 seconds"))
+            .unwrap();
+
+        assert_eq!(db.downloads("1 day"), 1);
+        assert_eq!(db.downloads("86402 seconds"), 2);
+
+        db.set_clock(FixedClock("2020-01-10 12:00:02".into()));
+        assert_eq!(db.downloads("1 day"), 0);
+        db.add_request("log", "0.4.8", true, 1).unwrap();
+        assert_eq!(download_times(&db).last().unwrap(), "2020-01-10 12:00:02.000");
+        assert_eq!(db.downloads("1 day"), 1);
+    }
+
+    #[test]
+    fn trending_crates_rank_the_risers() {
+        let db = test_db();
+        let at = |name: &str, count, when: &str| {
+            db.insert_requests(&request(name, "1.0.0", true, 1), count, &ago(when)).unwrap()
+        };
+        at("doubled", 5, "-36 hours");
+        at("doubled", 10, "-12 hours");
+        at("steady", 5, "-36 hours");
+        at("steady", 5, "-12 hours");
+        at("fading", 10, "-36 hours");
+        at("fading", 2, "-12 hours");
+        at("newcomer", 3, "-12 hours");
+
+        let trending = db.trending_crates("2 days", 10).unwrap();
+
+        assert_eq!(trending.len(), 2);
+        assert_eq!(trending[0], ("newcomer".to_string(), f64::INFINITY));
+        assert_eq!(trending[1].0, "doubled");
+        assert!((trending[1].1 - 2.0).abs() < 1e-9);
+        assert_eq!(db.trending_crates("2 days", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pushgateway_body_is_prometheus_text_grouped_by_job() {
+        let stats = Statistics {
+            downloads: 10,
+            hits: 7,
+            misses: 3,
+            bandwidth_saved: 4096,
+        };
+
+        let body = stats.as_pushgateway("cargo-cacher");
+
+        assert!(body.starts_with("# Pushgateway grouping: job=\"cargo-cacher\"\n"));
+        assert!(body.ends_with('\n'));
+        for line in body.lines().skip(1) {
+            if line.starts_with('#') {
+                assert!(line.starts_with("# TYPE cargo_cacher_") && line.ends_with(" gauge"));
+            } else {
+                let mut sample = line.split(' ');
+                assert!(sample.next().unwrap().starts_with("cargo_cacher_"));
+                sample.next().unwrap().parse::<i64>().unwrap();
+                assert_eq!(sample.next(), None);
+            }
+        }
+        assert!(body.contains("\ncargo_cacher_hits 7\n"));
+        assert!(body.contains("\ncargo_cacher_bandwidth_saved_bytes 4096\n"));
+        assert_eq!(encode_path_segment("nightly build/2"), "nightly%20build%2F2");
+
+        let mut collector = test_collector(test_db());
+        collector.pushgateway = Some(Pushgateway::new("http://127.0.0.1:9", "cargo-cacher"));
+        collector.aggregate.add(request("serde", "1.0.104", true, 1), 1);
+        collector.flush();
+        assert_eq!(download_rows(collector.db.as_ref().unwrap()), 1);
+        assert!(!collector.degraded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn version_breakdown_splits_traffic_per_version() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.103", true, 300), 3).unwrap();
+        db.add_requests(&request("serde", "1.0.103", false, 100), 1).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 200), 2).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 50), 1).unwrap();
+
+        let version = |version: &str, downloads, hits, bytes_saved| {
+            VersionStats { version: version.into(), downloads, hits, bytes_saved }
+        };
+        assert_eq!(db.version_breakdown("Serde", "1 day").unwrap(),
+                   [version("1.0.103", 4, 3, 300), version("1.0.104", 2, 0, 0)]);
+        assert!(db.version_breakdown("unknown", "1 day").unwrap().is_empty());
+    }
+
+    #[test]
+    fn outage_misses_are_counted_apart_from_cold_misses() {
+        let db = test_db();
+        let outage = CargoRequest { upstream_down: true, ..request("serde", "1.0.104", false, 0) };
+        db.add_requests(&outage, 2).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 100), 1).unwrap();
+        db.add_requests(&request("serde", "1.0.104", true, 100), 1).unwrap();
+
+        assert_eq!(db.outage_misses("1 day"), 2);
+        assert_eq!(totals(&db.stats_in("1 day")), (4, 1, 1, 100));
+        assert_eq!(db.worst_hit_ratio_crates("1 day", 1, 10).unwrap(),
+                   [("serde".to_string(), 0.5)]);
+    }
+
+    #[test]
+    fn avg_versions_per_crate_averages_over_crates() {
+        let db = test_db();
+        assert_eq!(db.avg_versions_per_crate(), 0.0);
+
+        db.record_cached("rand", "0.7.3").unwrap();
+        for version in &["1.0.102", "1.0.103", "1.0.104"] {
+            db.record_cached("serde", version).unwrap();
+        }
+
+        assert!((db.avg_versions_per_crate() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn queued_requests_are_recovered_after_a_crash() {
+        let path = temp_db_path("queue");
+        let queue = Arc::new(Mutex::new(RequestQueue::open(&path).unwrap()));
+        let (sender, receiver) = sync_channel(16);
+        // The collector died before writing anything out.
+        drop(receiver);
+        let stats = StatsSender {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+            commit_latency: Arc::new(Mutex::new(CommitLatency::default())),
+            queue: Some(queue.clone()),
+            degraded: Arc::new(AtomicBool::new(false)),
+        };
+        stats.send(request("serde", "1.0.104", true, 10));
+        stats.send(request("rand", "0.7.3", false, 20));
+        stats.send(request("log", "0.4.8", true, 30));
+        RequestQueue::discard_front(&queue, 1).unwrap();
+        drop(stats);
+        drop(queue);
+        // A trim interrupted before its rename leaves a partial copy behind.
+        fs::write(format!("{}.tmp", path), "{\"name\": \"rand\"").unwrap();
+
+        let db = test_db();
+        let mut queue = RequestQueue::open(&path).unwrap();
+        assert_eq!(queue.recover(&db).unwrap(), 2);
+        assert_eq!(totals(&db.stats_in("100 years")), (2, 1, 1, 30));
+        assert_eq!(queue.recover(&db).unwrap(), 0);
+        assert_eq!(download_rows(&db), 2);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(format!("{}.tmp", path));
+    }
+
+    #[test]
+    fn cache_leverage_divides_bytes_saved_by_bytes_stored() {
+        let db = test_db();
+        assert_eq!(db.cache_leverage(), 0.0);
+
+        db.add_requests(&request("serde", "1.0.104", false, 1_000), 1).unwrap();
+        db.add_requests(&request("serde", "1.0.104", true, 4_000), 4).unwrap();
+        db.add_requests(&request("rand", "0.7.3", false, 1_000), 1).unwrap();
+        let partial = CargoRequest { is_range: true, ..request("rand", "0.7.3", true, 10) };
+        db.add_requests(&partial, 1).unwrap();
+
+        assert_eq!(db.cached_bytes(), 2_000);
+        assert_eq!(db.bandwidth_saved_total(), 4_010);
+        assert!((db.cache_leverage() - 2.005).abs() < 1e-9);
+    }
+
+    #[test]
+    fn one_off_crates_were_downloaded_exactly_once() {
+        let db = test_db();
+        db.add_request("once", "1.0.0", false, 1).unwrap();
+        db.add_requests(&request("popular", "1.0.0", true, 1), 5).unwrap();
+        db.add_request("twice", "1.0.0", false, 1).unwrap();
+        db.add_request("twice", "1.0.1", false, 1).unwrap();
+        db.record_cached("never", "1.0.0").unwrap();
+
+        assert_eq!(db.one_off_crates().unwrap(), ["once"]);
+    }
+
+    #[test]
+    fn hourly_histogram_shifts_with_the_utc_offset() {
+        let mut db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 3, "2020-01-10 09:15:00")
+            .unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 2, "2020-01-09 23:30:00")
+            .unwrap();
+        let busy = |db: &Database| {
+            db.hourly_histogram("1 day")
+                .unwrap()
+                .into_iter()
+                .filter(|&(_, count)| count > 0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(busy(&db), [(9, 3), (23, 2)]);
+
+        db.set_utc_offset(parse_utc_offset("+02:00").unwrap());
+        assert_eq!(busy(&db), [(1, 2), (11, 3)]);
+        db.set_utc_offset(parse_utc_offset("-09:30").unwrap());
+        assert_eq!(busy(&db), [(14, 2), (23, 3)]);
+
+        assert_eq!(parse_utc_offset("+05:45"), Some(345));
+        assert_eq!(parse_utc_offset("+0530"), None);
+        assert_eq!(parse_utc_offset("+-2:00"), None);
+        assert_eq!(parse_utc_offset("+02:-30"), None);
+        assert_eq!(parse_utc_offset("+02:60"), None);
+    }
+
+    #[test]
+    fn flapping_crates_detects_alternating_hits_and_misses() {
+        let db = test_db();
+        let mut aggregate = Aggregate::new();
+        for &hit in &[true, false, true, false, true] {
+            aggregate.add(request("flappy", "1.0.0", hit, 1), 1);
+            aggregate.add(request("steady", "1.0.0", true, 1), 1);
+        }
+        aggregate.add(request("steady", "1.0.0", false, 1), 1);
+        aggregate.flush(&db).unwrap();
+
+        assert_eq!(db.flapping_crates("1 day", 4).unwrap(), ["flappy"]);
+        assert!(db.flapping_crates("1 day", 5).unwrap().is_empty());
+        assert_eq!(db.flapping_crates("1 day", 1).unwrap(), ["flappy", "steady"]);
+    }
+
+    #[test]
+    fn db_footprint_counts_rows_and_pages() {
+        let path = temp_db_path("footprint");
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        db.add_requests(&request("serde", "1.0.103", true, 1), 2).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 1), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 1), 1).unwrap();
+
+        let footprint = db.db_footprint().unwrap();
+
+        let rows = |table: &str| {
+            footprint.table_rows.iter().find(|(name, _)| name == table).map(|&(_, n)| n)
+        };
+        assert_eq!(rows("crates"), Some(2));
+        assert_eq!(rows("crate_versions"), Some(3));
+        assert_eq!(rows("downloads"), Some(3));
+        assert!(footprint.size_bytes > 0);
+        assert_eq!(footprint.size_bytes as u64, fs::metadata(&path).unwrap().len());
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn aggregate_only_mode_keeps_counters_but_no_rows() {
+        let mut db = test_db();
+        db.set_aggregate_only(true);
+        db.add_requests(&from_client("serde", "10.0.0.1"), 3).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 500), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 40), 2).unwrap();
+
+        assert_eq!(download_rows(&db), 0);
+        assert_eq!(totals(&db.stats()), (6, 5, 1, 41));
+    }
+
+    #[test]
+    fn median_crate_size_of_odd_and_even_row_counts() {
+        let db = test_db();
+        assert_eq!(db.median_crate_size("1 day").unwrap(), None);
+
+        db.add_request("tiny", "1.0.0", true, 10).unwrap();
+        db.add_requests(&request("medium", "1.0.0", true, 300), 3).unwrap();
+        db.add_request("huge", "1.0.0", false, 1_000_000).unwrap();
+        assert_eq!(db.median_crate_size("1 day").unwrap(), Some(100));
+
+        db.add_request("small", "1.0.0", true, 50).unwrap();
+        assert_eq!(db.median_crate_size("1 day").unwrap(), Some(75));
+    }
+
+    #[test]
+    fn shared_version_families_find_lockstep_releases() {
+        let db = test_db();
+        for name in &["tokio-core", "tokio-io", "tokio-net", "tokio-sync"] {
+            db.record_cached(name, "0.2.0-alpha.6").unwrap();
+        }
+        db.record_cached("serde", "1.0.0").unwrap();
+        db.record_cached("rand", "1.0.0").unwrap();
+        db.record_cached("log", "0.4.8").unwrap();
+
+        assert_eq!(db.shared_version_families(3).unwrap(), [("0.2.0-alpha.6".to_string(), 4)]);
+        assert_eq!(db.shared_version_families(2).unwrap(),
+                   [("0.2.0-alpha.6".to_string(), 4), ("1.0.0".to_string(), 2)]);
+    }
+
+    #[test]
+    fn failing_writes_degrade_to_memory_until_they_work_again() {
+        let mut collector = test_collector(test_db());
+        let (sender, _receiver) = sync_channel(1);
+        let stats = StatsSender {
+            sender,
+            dropped: collector.dropped.clone(),
+            commit_latency: collector.commit_latency.clone(),
+            queue: None,
+            degraded: collector.degraded.clone(),
+        };
+        collector.db.as_ref().unwrap().conn.execute_batch("PRAGMA query_only = ON").unwrap();
+
+        let logged = logged_by(|| {
+            for _ in 0..3 {
+                stats.send(request("serde", "1.0.104", true, 10));
+                collector.aggregate.add(request("serde", "1.0.104", true, 10), 1);
+                collector.flush();
+            }
+        });
+        assert_eq!(logged.iter().filter(|&&(level, _)| level == Level::Warn).count(), 1);
+        assert!(stats.is_degraded());
+        assert_eq!(stats.dropped_events(), 2);
+        assert_eq!(collector.aggregate.pending.len(), 1);
+        assert_eq!(download_rows(collector.db.as_ref().unwrap()), 0);
+
+        collector.db.as_ref().unwrap().conn.execute_batch("PRAGMA query_only = OFF").unwrap();
+        collector.flush();
+        assert!(!stats.is_degraded());
+        assert_eq!(totals(&collector.db.as_ref().unwrap().stats()), (3, 3, 0, 30));
+    }
+
+    #[test]
+    fn an_unopenable_database_degrades_until_it_opens() {
+        let dir = env::temp_dir().join(format!("cargo-cacher-{}-unopenable", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut collector = test_collector(test_db());
+        collector.db = None;
+        collector.db_path = Some(dir.join("stats.sqlite").to_str().unwrap().to_string());
+        let (sender, receiver) = sync_channel(16);
+        let stats = StatsSender {
+            sender,
+            dropped: collector.dropped.clone(),
+            commit_latency: collector.commit_latency.clone(),
+            queue: None,
+            degraded: collector.degraded.clone(),
+        };
+
+        let logged = logged_by(|| {
+            collector.start();
+            for _ in 0..3 {
+                stats.send(request("serde", "1.0.104", true, 10));
+                let req = receiver.try_recv().unwrap();
+                collector.aggregate.add(req, 1);
+                collector.flush();
+            }
+        });
+        assert_eq!(logged.iter().filter(|&&(level, _)| level == Level::Warn).count(), 1);
+        assert!(stats.is_degraded());
+        assert!(collector.db.is_none());
+        assert_eq!(collector.aggregate.pending.len(), 1);
+
+        fs::create_dir(&dir).unwrap();
+        collector.flush();
+        assert!(!stats.is_degraded());
+        assert_eq!(totals(&collector.db.as_ref().unwrap().stats()), (3, 3, 0, 30));
+        drop(collector);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn what_if_top_n_turns_the_long_tail_into_misses() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", true, 1_000), 10).unwrap();
+        db.add_requests(&request("serde", "1.0.104", false, 100), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 300), 3).unwrap();
+        db.add_requests(&request("log", "0.4.8", true, 50), 1).unwrap();
+        let real = totals(&db.stats_in("1 day"));
+
+        assert_eq!(totals(&db.what_if_top_n(1, "1 day").unwrap()), (15, 10, 5, 1_000));
+        assert_eq!(totals(&db.what_if_top_n(2, "1 day").unwrap()), (15, 13, 2, 1_300));
+        assert_eq!(totals(&db.what_if_top_n(3, "1 day").unwrap()), real);
+        assert_eq!(totals(&db.what_if_top_n(0, "1 day").unwrap()), (15, 0, 15, 0));
+    }
+
+    #[test]
+    fn newest_crates_come_first_and_merges_keep_the_earliest() {
+        let mut db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-3 days")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, &ago("-2 days")).unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 1), 1, &ago("-1 day")).unwrap();
+        db.add_request("serde", "1.0.105", false, 1).unwrap();
+
+        assert_eq!(db.newest_crates(2).unwrap(),
+                   [("log".to_string(), ago("-1 day") + ".000"),
+                    ("rand".to_string(), ago("-2 days") + ".000")]);
+
+        let path = temp_db_path("merge-first-seen");
+        {
+            let mut other =
+                Database::from_connection(Connection::open(&path).unwrap(), None).unwrap();
+            other.set_clock(FixedClock(NOW.into()));
+            other.set_aggregate_only(true);
+            other.insert_requests(&request("log", "0.4.8", true, 7), 2, &ago("-5 days")).unwrap();
+        }
+        db.merge_from(&path).unwrap();
+
+        assert_eq!(db.newest_crates(1).unwrap(),
+                   [("rand".to_string(), ago("-2 days") + ".000")]);
+        db.set_aggregate_only(true);
+        assert_eq!(totals(&db.stats()), (2, 2, 0, 7));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn first_seen_is_backfilled_once_and_opening_a_locked_database_fails_cleanly() {
+        let path = temp_db_path("first-seen-migration");
+        let conn = Connection::open(&path).unwrap();
+        let db = Database::from_connection(conn, None).unwrap();
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 1, &ago("-3 days")).unwrap();
+        // As left behind by a build from before first_seen was recorded.
+        db.conn.execute_batch("UPDATE crates SET first_seen = NULL; PRAGMA user_version = 0;")
+            .unwrap();
+        drop(db);
+
+        let writer = Connection::open(&path).unwrap();
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let impatient = Connection::open(&path).unwrap();
+        impatient.busy_timeout(Duration::from_millis(10)).unwrap();
+        assert!(Database::from_connection(impatient, None).is_err());
+        writer.execute_batch("ROLLBACK").unwrap();
+
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.newest_crates(1).unwrap(),
+                   [("serde".to_string(), ago("-3 days") + ".000")]);
+        drop(db);
+        writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+        assert!(Database::new(Some(path.as_str()), None).is_ok());
+        writer.execute_batch("ROLLBACK").unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn optimize_fills_the_planner_statistics() {
+        let db = test_db();
+        for i in 0..20 {
+            db.add_request(format!("crate-{}", i), "1.0.0", i % 2 == 0, 1).unwrap();
+        }
+
+        db.optimize().unwrap();
+
+        let analyzed: i64 = db.conn
+            .query_row("SELECT count(*) FROM sqlite_stat1 WHERE tbl = 'downloads'",
+                       params![],
+                       |row| row.get(0))
+            .unwrap();
+        assert!(analyzed > 0);
+        assert!(!db.tables().unwrap().iter().any(|table| table.starts_with("sqlite_")));
+    }
+
+    #[test]
+    fn downloads_in_size_range_isolates_the_heavy_tail() {
+        const MIB: i64 = 1024 * 1024;
+        let db = test_db();
+        db.add_requests(&request("small", "1.0.0", true, 50_000), 5).unwrap();
+        db.add_requests(&request("exactly-ten", "1.0.0", true, 10 * MIB), 1).unwrap();
+        db.add_requests(&request("huge", "1.0.0", false, 80 * MIB), 2).unwrap();
+
+        assert_eq!(db.downloads_in_size_range(10 * MIB, None, "1 day").unwrap(), 3);
+        assert_eq!(db.downloads_in_size_range(0, Some(10 * MIB), "1 day").unwrap(), 5);
+        assert_eq!(db.downloads_in_size_range(20 * MIB, Some(50 * MIB), "1 day").unwrap(), 2);
+        assert_eq!(db.downloads_in_size_range(0, None, "1 day").unwrap(), 8);
+    }
+
+    #[test]
+    fn always_failing_crates_never_succeeded() {
+        let db = test_db();
+        let failed = |name: &str| CargoRequest { failed: true, ..request(name, "1.0.0", false, 0) };
+        db.add_requests(&failed("serdee"), 3).unwrap();
+        db.add_requests(&failed("flaky"), 2).unwrap();
+        db.add_requests(&request("flaky", "1.0.0", false, 100), 1).unwrap();
+        db.add_requests(&request("serde", "1.0.0", true, 100), 1).unwrap();
+
+        assert_eq!(db.always_failing_crates("1 day").unwrap(), ["serdee"]);
+    }
+
+    #[test]
+    fn bandwidth_by_client_ranks_the_heaviest_first() {
+        let db = test_db();
+        let sized = |client, size| CargoRequest { size, ..from_client("serde", client) };
+        db.add_requests(&sized("ci-runner-1", 9_000), 3).unwrap();
+        db.add_requests(&sized("ci-runner-1", 1_000), 1).unwrap();
+        db.add_requests(&sized("laptop", 500), 1).unwrap();
+        db.add_requests(&sized("ci-runner-2", 4_000), 2).unwrap();
+        db.add_requests(&request("rand", "0.7.3", false, 2_000), 1).unwrap();
+
+        assert_eq!(db.bandwidth_by_client("1 day", 10).unwrap(),
+                   [("ci-runner-1".to_string(), 10_000),
+                    ("ci-runner-2".to_string(), 4_000),
+                    ("unknown".to_string(), 2_000),
+                    ("laptop".to_string(), 500)]);
+        assert_eq!(db.bandwidth_by_client("1 day", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn longest_hit_streak_follows_the_request_order() {
+        let db = test_db();
+        let sequence = "HHMHHBHHMH";
+        for (i, kind) in sequence.chars().enumerate() {
+            let req = CargoRequest {
+                bypass: kind == 'B',
+                ..request("serde", "1.0.0", kind != 'M', 1)
+            };
+            db.insert_requests(&req, 1, &ago(&format!("-{} minutes", sequence.len() - i)))
+                .unwrap();
+        }
+        assert_eq!(db.longest_hit_streak("1 day").unwrap(), 4);
+        assert_eq!(test_db().longest_hit_streak("1 day").unwrap(), 0);
+
+        let alternating = test_db();
+        let mut aggregate = Aggregate::new();
+        for i in 0..6 {
+            aggregate.add(request("rand", "0.7.3", i % 2 == 0, 1), 1);
+        }
+        aggregate.flush(&alternating).unwrap();
+        assert_eq!(alternating.longest_hit_streak("1 day").unwrap(), 1);
+    }
+
+    #[test]
+    fn avg_requests_per_crate_per_day_normalizes_by_crates_and_days() {
+        let db = test_db();
+        assert_eq!(db.avg_requests_per_crate_per_day(7), 0.0);
+
+        db.insert_requests(&request("serde", "1.0.104", true, 1), 30, &ago("-2 days")).unwrap();
+        db.insert_requests(&request("serde", "1.0.103", true, 1), 12, &ago("-5 days")).unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 14, &ago("-1 day")).unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 1), 100, &ago("-8 days")).unwrap();
+
+        assert!((db.avg_requests_per_crate_per_day(7) - 4.0).abs() < 1e-9);
+        assert_eq!(db.avg_requests_per_crate_per_day(0), 0.0);
+    }
+
+    #[test]
+    fn unrequested_prewarms_lists_cached_versions_nobody_pulled() {
+        let db = test_db();
+        db.record_cached("serde", "1.0.104").unwrap();
+        db.record_cached("serde", "1.0.103").unwrap();
+        db.add_request("serde", "1.0.104", true, 1).unwrap();
+        db.add_request("rand", "0.7.3", false, 1).unwrap();
+
+        assert_eq!(db.unrequested_prewarms().unwrap(), pairs(&[("serde", "1.0.103")]));
+    }
+
+    #[test]
+    fn daily_traffic_cv_contrasts_steady_and_spiky_traffic() {
+        let steady = test_db();
+        let spiky = test_db();
+        for day in 1..8 {
+            let when = ago(&format!("-{} days", day));
+            steady.insert_requests(&request("serde", "1.0.104", true, 1), 10, &when).unwrap();
+        }
+        spiky.insert_requests(&request("serde", "1.0.104", true, 1), 70, &ago("-3 days")).unwrap();
+        // Today is incomplete and left out.
+        spiky.add_requests(&request("serde", "1.0.104", true, 1), 1_000).unwrap();
+
+        assert!(steady.daily_traffic_cv(7).unwrap().abs() < 1e-9);
+        assert!((spiky.daily_traffic_cv(7).unwrap() - 6f64.sqrt()).abs() < 1e-9);
+        assert_eq!(test_db().daily_traffic_cv(7).unwrap(), 0.0);
+        assert!(steady.daily_traffic_cv(0).is_err());
+    }
+
+    #[test]
+    fn hit_miss_hourly_pairs_the_counts_of_each_hour() {
+        let db = test_db();
+        let at = |hit, count, time: &str| {
+            db.insert_requests(&request("serde", "1.0.104", hit, 1), count, time).unwrap()
+        };
+        at(true, 4, "2020-01-10 09:05:00");
+        at(false, 1, "2020-01-10 09:55:00");
+        at(false, 2, "2020-01-10 07:30:00");
+        at(true, 3, "2020-01-10 11:00:00");
+        let outage = CargoRequest { upstream_down: true, ..request("rand", "0.7.3", false, 0) };
+        db.insert_requests(&outage, 5, "2020-01-10 11:10:00").unwrap();
+
+        assert_eq!(db.hit_miss_hourly("1 day").unwrap(),
+                   [("2020-01-10 07:00:00".to_string(), 0, 2),
+                    ("2020-01-10 09:00:00".to_string(), 4, 1),
+                    ("2020-01-10 11:00:00".to_string(), 3, 0)]);
+    }
+
+    #[test]
+    fn cumulative_distinct_crates_grows_to_the_catalog_size() {
+        let db = test_db();
+        db.insert_requests(&request("old", "1.0.0", true, 1), 1, &ago("-10 days")).unwrap();
+        db.insert_requests(&request("serde", "1.0.0", true, 1), 1, &ago("-3 days")).unwrap();
+        db.insert_requests(&request("rand", "1.0.0", true, 1), 1, &ago("-3 days")).unwrap();
+        db.insert_requests(&request("serde", "1.0.1", true, 1), 1, &ago("-2 days")).unwrap();
+        db.insert_requests(&request("log", "1.0.0", true, 1), 1, &ago("-1 day")).unwrap();
+
+        let series = db.cumulative_distinct_crates("7 days", "day").unwrap();
+
+        assert_eq!(series, [("2020-01-07".to_string(), 3), ("2020-01-09".to_string(), 4)]);
+        assert!(series.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(series.last().unwrap().1, db.distinct_crates().unwrap());
+    }
+
+    #[test]
+    fn dedupe_crates_merges_case_variants_into_the_lowercase_name() {
+        let db = test_db();
+        db.add_requests(&request("first", "1.0.104", true, 10), 2).unwrap();
+        db.add_requests(&request("second", "1.0.104", false, 10), 1).unwrap();
+        db.add_requests(&request("third", "1.0.103", true, 5), 1).unwrap();
+        db.add_requests(&request("serde_json", "1.0.44", true, 1), 1).unwrap();
+        db.add_requests(&request("serde-json", "1.0.44", true, 1), 1).unwrap();
+        // As left behind by builds that compared names case-sensitively.
+        db.conn
+            .execute_batch("DROP INDEX unique_crate_names_nocase;
+                CREATE UNIQUE INDEX unique_crate_names ON crates (name);
+                UPDATE crates SET name = 'Serde' WHERE name = 'first';
+                UPDATE crates SET name = 'SERDE' WHERE name = 'second';
+                UPDATE crates SET name = 'serde' WHERE name = 'third';")
+            .unwrap();
+
+        assert_eq!(db.dedupe_crates().unwrap(), 2);
+
+        let mut names: Vec<String> = db.crates().unwrap().into_iter().map(|c| c.name).collect();
+        names.sort();
+        assert_eq!(names, ["serde", "serde-json", "serde_json"]);
+        assert_eq!(db.all_versions().unwrap(),
+                   pairs(&[("serde", "1.0.103"),
+                           ("serde", "1.0.104"),
+                           ("serde-json", "1.0.44"),
+                           ("serde_json", "1.0.44")]));
+        let serde = db.stats_for_crates(&["serde".to_string()], "1 day").unwrap();
+        assert_eq!(totals(&serde), (4, 3, 1, 15));
+        let indexed: i64 = db.conn
+            .query_row("SELECT count(*) FROM sqlite_master
+                WHERE type = 'index' AND name = 'unique_crate_names_nocase'",
+                       params![],
+                       |row| row.get(0))
+            .unwrap();
+        assert_eq!(indexed, 1);
+        assert_eq!(db.dedupe_crates().unwrap(), 0);
+    }
+
+    #[test]
+    fn top_percent_byte_share_finds_the_heavy_tail() {
+        let db = test_db();
+        db.add_requests(&request("small", "1.0.0", true, 9_900), 99).unwrap();
+        db.add_requests(&request("huge", "1.0.0", false, 1_000_000), 1).unwrap();
+
+        let share = db.top_percent_byte_share(1.0, "1 day").unwrap();
+        assert!((share - 1_000_000.0 / 1_009_900.0).abs() < 1e-9);
+        assert!(share > 0.99);
+        let half = db.top_percent_byte_share(50.0, "1 day").unwrap();
+        assert!((half - 1_004_900.0 / 1_009_900.0).abs() < 1e-9);
+        assert!((db.top_percent_byte_share(100.0, "1 day").unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(test_db().top_percent_byte_share(1.0, "1 day").unwrap(), 0.0);
+        assert!(db.top_percent_byte_share(0.0, "1 day").is_err());
+    }
+
+    #[test]
+    fn all_versions_returns_every_known_pair() {
+        let db = test_db();
+        db.record_cached("serde", "1.0.104").unwrap();
+        db.add_request("rand", "0.7.3", true, 1).unwrap();
+        db.add_request("rand", "0.6.5", false, 1).unwrap();
+        db.add_request("serde", "1.0.103", false, 1).unwrap();
+        let expected = pairs(&[("rand", "0.6.5"),
+                               ("rand", "0.7.3"),
+                               ("serde", "1.0.103"),
+                               ("serde", "1.0.104")]);
+
+        assert_eq!(db.all_versions().unwrap(), expected);
+        let mut streamed = Vec::new();
+        db.for_each_version(|name, version| streamed.push((name, version))).unwrap();
+        assert_eq!(streamed, expected);
+        assert!(test_db().all_versions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn avg_bytes_saved_per_hit_leaves_out_misses_and_bypasses() {
+        let db = test_db();
+        db.add_requests(&request("rand", "0.7.3", false, 5_000), 1).unwrap();
+        assert_eq!(db.avg_bytes_saved_per_hit("1 day"), 0.0);
+
+        db.add_requests(&request("serde", "1.0.104", true, 3_000), 3).unwrap();
+        db.add_requests(&request("log", "0.4.8", true, 500), 1).unwrap();
+        let bypass = CargoRequest { bypass: true, ..request("serde", "1.0.104", true, 90_000) };
+        db.add_requests(&bypass, 1).unwrap();
+
+        assert!((db.avg_bytes_saved_per_hit("1 day") - 875.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn target_breakdown_counts_requests_per_build_target() {
+        let db = test_db();
+        let for_target = |target: &str| {
+            CargoRequest { target: Some(target.into()), ..request("serde", "1.0.104", true, 1) }
+        };
+        db.add_requests(&for_target("x86_64-unknown-linux-gnu"), 5).unwrap();
+        db.add_requests(&for_target("aarch64-apple-darwin"), 2).unwrap();
+        db.add_requests(&for_target("aarch64-apple-darwin"), 1).unwrap();
+        db.add_requests(&request("rand", "0.7.3", true, 1), 1).unwrap();
+
+        assert_eq!(db.target_breakdown("1 day").unwrap(),
+                   [("x86_64-unknown-linux-gnu".to_string(), 5),
+                    ("aarch64-apple-darwin".to_string(), 3),
+                    ("unknown".to_string(), 1)]);
+    }
+
+    #[test]
+    fn warm_conversion_rate_counts_misses_hit_soon_after() {
+        let db = test_db();
+        let at = |name: &str, hit, when: &str| {
+            db.insert_requests(&request(name, "1.0.0", hit, 1), 1, &ago(when)).unwrap()
+        };
+        at("warmed", false, "-5 hours");
+        at("warmed", true, "-270 minutes");
+        at("late", false, "-5 hours");
+        at("late", true, "-2 hours");
+        at("cold", false, "-3 hours");
+        at("hot", true, "-1 hour");
+
+        assert!((db.warm_conversion_rate("1 hour", "1 day").unwrap() - 1.0 / 3.0).abs() < 1e-9);
+        assert!((db.warm_conversion_rate("4 hours", "1 day").unwrap() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(test_db().warm_conversion_rate("1 hour", "1 day").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn salted_clients_are_stored_as_hmacs_and_still_group() {
+        let mut db = test_db();
+        db.set_client_salt(Some("key"));
+        let client = "The quick brown fox jumps over the lazy dog";
+        let hashed = "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        db.add_requests(&from_client("serde", client), 2).unwrap();
+        db.add_requests(&from_client("rand", client), 1).unwrap();
+        db.add_requests(&from_client("serde", "10.0.0.2"), 1).unwrap();
+
+        let stored: Vec<String> = db.conn
+            .prepare("SELECT DISTINCT client FROM downloads ORDER BY client")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.contains(&hashed.to_string()));
+        assert!(!stored.iter().any(|stored| stored == client || stored == "10.0.0.2"));
+        assert_eq!(db.bandwidth_by_client("1 day", 1).unwrap(), [(hashed.to_string(), 2)]);
+        assert_eq!(db.distinct_client_crate_pairs("1 day").unwrap(), 3);
+    }
+
+    #[test]
+    fn crate_retention_follows_the_earlier_cohort() {
+        let db = test_db();
+        let at = |name: &str, when: &str| {
+            db.insert_requests(&request(name, "1.0.0", true, 1), 1, &ago(when)).unwrap()
+        };
+        at("retained", "-100 days");
+        at("retained", "-10 days");
+        at("churned", "-120 days");
+        at("ancient", "-200 days");
+        at("ancient", "-1 day");
+        at("newcomer", "-5 days");
+
+        assert!((db.crate_retention("90 days").unwrap() - 0.5).abs() < 1e-9);
+        assert_eq!(test_db().crate_retention("90 days").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn schema_version_reports_the_migrated_version() {
+        assert_eq!(test_db().schema_version().unwrap(), SCHEMA_VERSION);
+
+        let path = temp_db_path("schema-version");
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        db.conn.pragma_update(None, "user_version", &(SCHEMA_VERSION + 1)).unwrap();
+        drop(db);
+
+        let db = Database::new(Some(path.as_str()), None).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION + 1);
+        drop(db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stickiest_crates_reward_daily_use_over_bursts() {
+        let db = test_db();
+        for day in 0..5 {
+            let when = ago(&format!("-{} days", day));
+            db.insert_requests(&request("daily", "1.0.0", true, 1), 1, &when).unwrap();
+        }
+        db.insert_requests(&request("burst", "1.0.0", true, 1), 500, &ago("-2 days")).unwrap();
+        db.insert_requests(&request("burst", "1.0.0", true, 1), 500, &ago("-49 hours")).unwrap();
+
+        assert_eq!(db.stickiest_crates("7 days", 10).unwrap(),
+                   [("daily".to_string(), 5), ("burst".to_string(), 1)]);
+        assert_eq!(db.stickiest_crates("7 days", 1).unwrap(), [("daily".to_string(), 5)]);
+    }
+
+    #[test]
+    fn byte_rate_spreads_recent_bytes_over_the_minutes() {
+        let db = test_db();
+        db.insert_requests(&request("serde", "1.0.104", false, 6_000_000), 2, &ago("-5 minutes"))
+            .unwrap();
+        db.insert_requests(&request("rand", "0.7.3", true, 3_000_000), 1, &ago("-1 minutes"))
+            .unwrap();
+        db.insert_requests(&request("log", "0.4.8", true, 50_000_000), 1, &ago("-2 hours"))
+            .unwrap();
+
+        assert!((db.byte_rate(10) - 900_000.0).abs() < 1e-9);
+        assert!((db.byte_rate(2) - 1_500_000.0).abs() < 1e-9);
+        assert_eq!(db.byte_rate(0), 0.0);
+    }
+
+    #[test]
+    fn fattest_crates_rank_by_average_size() {
+        let db = test_db();
+        db.add_requests(&request("popular", "1.0.0", true, 100 * 20_000), 100).unwrap();
+        db.add_requests(&request("rare-but-huge", "1.0.0", false, 90_000_000), 1).unwrap();
+        db.add_requests(&request("medium", "1.0.0", true, 2 * 500_000), 2).unwrap();
+        let partial = CargoRequest { is_range: true, ..request("medium", "1.0.0", true, 10) };
+        db.add_requests(&partial, 5).unwrap();
+
+        assert_eq!(db.fattest_crates("1 day", 10).unwrap(),
+                   [("rare-but-huge".to_string(), 90_000_000.0),
+                    ("medium".to_string(), 500_000.0),
+                    ("popular".to_string(), 20_000.0)]);
+        assert_eq!(db.fattest_crates("1 day", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn failure_rate_series_divides_failures_by_requests() {
+        let db = test_db();
+        let at = |failed, count, time: &str| {
+            let req = CargoRequest { failed, ..request("serde", "1.0.104", false, 1) };
+            db.insert_requests(&req, count, time).unwrap()
+        };
+        at(false, 4, "2020-01-10 08:10:00");
+        at(true, 3, "2020-01-10 10:20:00");
+        at(false, 1, "2020-01-10 10:40:00");
+        at(true, 2, "2020-01-10 11:00:00");
+
+        assert_eq!(db.failure_rate_series("1 day", "hour").unwrap(),
+                   [("2020-01-10 08:00:00".to_string(), 0.0),
+                    ("2020-01-10 10:00:00".to_string(), 0.75),
+                    ("2020-01-10 11:00:00".to_string(), 1.0)]);
+        assert!(db.failure_rate_series("1 day", "fortnight").is_err());
+    }
+
+    #[test]
+    fn versions_today_skips_earlier_days() {
+        let mut db = test_db();
+        let at = |db: &Database, version: &str, time: &str| {
+            db.insert_requests(&request("serde", version, true, 1), 1, time).unwrap()
+        };
+        at(&db, "1.0.104", "2020-01-10 00:30:00");
+        at(&db, "1.0.103", "2020-01-10 11:00:00");
+        at(&db, "1.0.103", "2020-01-10 11:30:00");
+        at(&db, "1.0.102", "2020-01-09 23:30:00");
+        db.insert_requests(&request("rand", "0.7.3", true, 1), 1, NOW).unwrap();
+
+        assert_eq!(db.versions_today("Serde").unwrap(), ["1.0.103", "1.0.104"]);
+        db.set_utc_offset(-60);
+        assert_eq!(db.versions_today("serde").unwrap(), ["1.0.103"]);
+        db.set_utc_offset(0);
+        at(&db, "1.0.99", "2020-01-10 09:00:00");
+        at(&db, "nightly", "2020-01-10 09:00:00");
+        assert_eq!(db.versions_today("serde").unwrap(),
+                   ["1.0.99", "1.0.103", "1.0.104", "nightly"]);
+    }
+
+    #[test]
+    fn client_concentration_hhi_contrasts_one_dominant_client() {
+        let dominated = test_db();
+        dominated.add_requests(&from_client("serde", "runaway-ci"), 90).unwrap();
+        dominated.add_requests(&from_client("serde", "laptop"), 10).unwrap();
+        let even = test_db();
+        for i in 0..10 {
+            even.add_requests(&from_client("serde", &format!("runner-{}", i)), 10).unwrap();
+        }
+        even.add_requests(&request("rand", "0.7.3", true, 1), 1_000).unwrap();
+
+        assert!((dominated.client_concentration_hhi("1 day").unwrap() - 0.82).abs() < 1e-9);
+        assert!((even.client_concentration_hhi("1 day").unwrap() - 0.1).abs() < 1e-9);
+        assert_eq!(test_db().client_concentration_hhi("1 day").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn slowest_fetches_rank_the_slow_misses_first() {
+        let db = test_db();
+        let fetched = |name: &str, hit, ms| {
+            CargoRequest { fetch_ms: Some(ms), ..request(name, "1.0.0", hit, 1) }
+        };
+        db.add_requests(&fetched("fast", false, 40), 3).unwrap();
+        db.add_requests(&fetched("slow", false, 2_000), 1).unwrap();
+        db.add_requests(&fetched("slow", false, 1_000), 1).unwrap();
+        db.add_requests(&fetched("hit", true, 9_000), 1).unwrap();
+        db.add_requests(&request("untimed", "1.0.0", false, 1), 1).unwrap();
+
+        assert_eq!(db.slowest_fetches("1 day", 10).unwrap(),
+                   [("slow".to_string(), "1.0.0".to_string(), 1_500.0),
+                    ("fast".to_string(), "1.0.0".to_string(), 40.0)]);
+        assert_eq!(db.slowest_fetches("1 day", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn crate_hit_coverage_is_the_share_of_crates_ever_hit() {
+        let db = test_db();
+        assert_eq!(db.crate_hit_coverage().unwrap(), 0.0);
+
+        db.add_request("serde", "1.0.104", false, 1).unwrap();
+        db.add_request("serde", "1.0.104", true, 1).unwrap();
+        db.add_request("rand", "0.7.3", false, 1).unwrap();
+        let bypass = CargoRequest { bypass: true, ..request("log", "0.4.8", true, 1) };
+        db.add_requests(&bypass, 1).unwrap();
+        db.record_cached("libc", "0.2.66").unwrap();
+
+        assert!((db.crate_hit_coverage().unwrap() - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stats_for_crates_excludes_unlisted_crates() {
+        let db = test_db();
+        db.add_requests(&request("serde", "1.0.104", true, 100), 3).unwrap();
+        db.add_requests(&request("rand", "0.7.3", false, 10), 1).unwrap();
+        db.add_requests(&request("log", "0.4.8", true, 1_000), 5).unwrap();
+        let names = ["Serde".to_string(), "rand".to_string(), "unknown') OR 1=1 --".to_string()];
+
+        assert_eq!(totals(&db.stats_for_crates(&names, "1 day").unwrap()), (4, 3, 1, 100));
+        assert_eq!(totals(&db.stats_for_crates(&[], "1 day").unwrap()), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn weekly_digest_is_populated_from_the_last_two_weeks() {
+        let db = test_db();
+        let at = |name: &str, hit, size, count, when: &str| {
+            db.insert_requests(&request(name, "1.0.0", hit, size), count, &ago(when)).unwrap()
+        };
+        at("serde", true, 10_000, 10, "-1 day");
+        at("serde", false, 1_000, 1, "-2 days");
+        at("rand", false, 6, 6, "-1 day");
+        at("rand", true, 500, 1, "-3 days");
+        for name in &["a", "b", "c", "d", "e"] {
+            at(name, true, 1, 1, "-1 day");
+        }
+        at("serde", true, 4_000, 4, "-10 days");
+
+        let digest = db.weekly_digest().unwrap();
+
+        assert_eq!(totals(&digest.totals), (23, 16, 7, 10_505));
+        assert_eq!(digest.top_crates.len(), 5);
+        assert_eq!(digest.top_crates[..2], [("serde".to_string(), 11), ("rand".to_string(), 7)]);
+        assert_eq!(digest.biggest_saver, Some(("serde".to_string(), 10_000)));
+        assert_eq!(digest.worst_hit_ratio, Some(("rand".to_string(), 1.0 / 7.0)));
+        assert_eq!((digest.downloads_delta,
+                    digest.hits_delta,
+                    digest.misses_delta,
+                    digest.bandwidth_saved_delta),
+                   (19, 12, 7, 6_505));
+        let json = serde_json::to_value(&digest).unwrap();
+        assert_eq!(json["totals"]["bandwidth_saved"], 10_505);
+        assert_eq!(json["top_crates"][0], json!(["serde", 11]));
+        assert_eq!(json["biggest_saver"], json!(["serde", 10_000]));
+        assert_eq!(json["hits_delta"], 12);
+    }
 }